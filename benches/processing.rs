@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use csv::{ReaderBuilder, Trim, Writer};
+
+use transponster::engine::generator::generate_transactions;
+use transponster::engine::Engine;
+
+fn generate_csv(n: usize) -> Vec<u8> {
+    let mut writer = Writer::from_writer(Vec::new());
+    generate_transactions(n, 42)
+        .into_iter()
+        .try_for_each(|transaction| writer.serialize(transaction))
+        .unwrap();
+
+    writer.into_inner().unwrap()
+}
+
+fn process_from_reader(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process_from_reader");
+
+    for n in [10_000, 100_000] {
+        let input = generate_csv(n);
+
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &input, |b, input| {
+            b.iter(|| {
+                let reader = ReaderBuilder::new()
+                    .flexible(true)
+                    .trim(Trim::All)
+                    .from_reader(input.as_slice());
+
+                let mut engine = Engine::new();
+                engine.process_from_reader(reader).unwrap();
+                engine
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, process_from_reader);
+criterion_main!(benches);
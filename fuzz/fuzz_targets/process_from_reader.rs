@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use transponster::engine::config::EngineConfig;
+use transponster::engine::{reader_builder, Engine};
+
+// Arbitrary bytes must only ever produce errors or skipped rows, never a panic.
+// The first byte toggles engine options, so their code paths get covered too.
+fuzz_target!(|data: &[u8]| {
+    let Some((flags, input)) = data.split_first() else {
+        return;
+    };
+
+    let mut engine = Engine::with_config(EngineConfig {
+        skip_bad_rows: true,
+        strict: flags & 1 != 0,
+        amounts_in_minor_units: flags & 2 != 0,
+        store_withdrawals_for_dispute: flags & 4 == 0,
+        check_chronology: flags & 8 != 0,
+        decimal_places: u32::from(flags >> 4),
+        ..Default::default()
+    });
+    engine.set_error_sink(Box::new(std::io::sink()));
+
+    let _ = engine.process_from_reader(reader_builder().from_reader(input));
+    let _ = engine.verify_invariants();
+});
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::hash::Hash;
+
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+
+// Money representation used by the engine. Input is parsed as `Decimal` and converted,
+// reports, warnings and errors convert back, so only the accounting itself is generic.
+pub trait Amount: Copy + Ord + Hash + fmt::Debug + fmt::Display + Serialize + 'static {
+    const ZERO: Self;
+
+    // None on overflow
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    // Only for statistics, which must not fail
+    fn saturating_add(self, other: Self) -> Self;
+
+    // None if the value can't be represented exactly (e.g. too many decimal places)
+    fn from_decimal(value: Decimal) -> Option<Self>;
+    fn to_decimal(self) -> Decimal;
+
+    // Canonical form, e.g. without trailing zeros
+    fn normalize(self) -> Self {
+        self
+    }
+}
+
+impl Amount for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        Decimal::saturating_add(self, other)
+    }
+
+    fn from_decimal(value: Decimal) -> Option<Self> {
+        Some(value)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+
+    fn normalize(self) -> Self {
+        Decimal::normalize(&self)
+    }
+}
+
+// Fixed point amount counting 1/10000 units, integer arithmetic only. Amounts with more
+// than 4 decimal places can't be represented and are rejected when parsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinorUnits(pub i128);
+
+impl MinorUnits {
+    pub const SCALE: u32 = 4;
+    // Largest magnitude a `Decimal` mantissa can hold, so every value converts back
+    const LIMIT: i128 = (1 << 96) - 1;
+
+    fn within_limit(value: i128) -> Option<Self> {
+        (value.abs() <= Self::LIMIT).then_some(Self(value))
+    }
+}
+
+impl Amount for MinorUnits {
+    const ZERO: Self = MinorUnits(0);
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).and_then(Self::within_limit)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).and_then(Self::within_limit)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        Self(
+            self.0
+                .saturating_add(other.0)
+                .clamp(-Self::LIMIT, Self::LIMIT),
+        )
+    }
+
+    fn from_decimal(value: Decimal) -> Option<Self> {
+        let value = value.normalize();
+        let missing_scale = Self::SCALE.checked_sub(value.scale())?;
+        value
+            .mantissa()
+            .checked_mul(10_i128.pow(missing_scale))
+            .and_then(Self::within_limit)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        Decimal::from_i128_with_scale(self.0, Self::SCALE)
+    }
+}
+
+impl fmt::Display for MinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_decimal().normalize().fmt(f)
+    }
+}
+
+// Same text as the equivalent `Decimal`, e.g. in a replay log
+impl Serialize for MinorUnits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Serialize::serialize(&self.to_decimal().normalize(), serializer)
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+use super::error::EngineError;
+use super::models::{ClientId, ReportRow};
+
+// Engine configuration. Defaults keep the original behavior.
+// Can be loaded from a TOML or JSON file, missing keys take default values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    // Monetary precision used for input validation and output rounding
+    pub decimal_places: u32,
+    // Reject input that would otherwise be tolerated (e.g. too precise amounts)
+    pub strict: bool,
+    // When disabled withdrawals are not stored, only their ids are kept for deduplication,
+    // so disputing one fails with `MissingTransaction`. Saves memory on withdrawal heavy
+    // input. Formerly `withdrawal_disputes`, still accepted in config files.
+    #[serde(alias = "withdrawal_disputes")]
+    pub store_withdrawals_for_dispute: bool,
+    // Input amounts are integer counts of 1/10000 units
+    pub amounts_in_minor_units: bool,
+    pub log_format: LogFormat,
+    // Reject rows with a timestamp earlier than the latest one seen so far
+    pub check_chronology: bool,
+    // Log and skip rows that can't be parsed instead of aborting
+    pub skip_bad_rows: bool,
+    // Rows of clients outside of the allowlist or in the denylist are ignored
+    pub only_clients: Option<HashSet<ClientId>>,
+    pub exclude_clients: Option<HashSet<ClientId>>,
+    // Deposits leaving more available than this are reported as warnings
+    pub balance_warning_threshold: Option<Decimal>,
+    // Deposits leaving a higher total (available plus held) are rejected
+    pub max_account_balance: Option<Decimal>,
+    // Print the number of records read every that many records and at the end
+    pub progress_interval: Option<usize>,
+    // Log answers to query rows
+    pub verbose: bool,
+    // Append a footer row with totals across all accounts to the report
+    pub report_totals: bool,
+    // Reject deposits and withdrawals of zero, they'd only be stored as no-ops
+    pub reject_zero_amount: bool,
+    // Decimal separator used in the amount column
+    pub decimal_locale: DecimalLocale,
+    // Warn when a disputed withdrawal raises the total above lifetime net deposits
+    pub warn_unbacked_disputes: bool,
+    // Flush the report writer every that many rows instead of only at the end
+    pub report_flush_interval: Option<usize>,
+    // Resolves of existing but not disputed transactions are ignored instead of rejected
+    pub lenient_resolves: bool,
+    // Disputed deposits hold at most the available funds, instead of making them negative
+    pub clamp_available_on_dispute: bool,
+    // Strip trailing zeros of amounts and report values, so `1.0` and `1.00` are both `1`
+    pub normalize_amounts: bool,
+    // How many times a transaction can be disputed, resolved disputes included
+    pub max_disputes_per_transaction: Option<u32>,
+    // Keep a per account log of dispute events, costs memory for every dispute
+    pub record_events: bool,
+    // Reject rows identical (operation, client, tx and amount) to an applied one, e.g.
+    // when the same file is processed twice
+    pub dedupe_fingerprints: bool,
+    // A deposit and a withdrawal can share an id, duplicates are only checked within
+    // the same operation type
+    pub dedupe_per_operation: bool,
+    // A deposit or withdrawal can reuse the id of a stored transaction whose disputes are
+    // all settled (resolved or charged back), replacing it, see `AccountData::is_settled`
+    pub reuse_settled_ids: bool,
+    // Reject dispute and resolve rows carrying an amount instead of ignoring it
+    pub reject_dispute_amounts: bool,
+    // Stop reading after that many records
+    pub record_limit: Option<usize>,
+    // Rows that can't be parsed count towards `record_limit`
+    pub limit_counts_malformed: bool,
+    // Add the number of open disputes to every report row
+    pub detailed_report: bool,
+    // Don't write processing errors and progress to the error log or as tracing events,
+    // errors are still counted
+    pub quiet: bool,
+    // Format of files read by `Engine::process_input`
+    pub input_format: InputFormat,
+    // Pick the csv delimiter (comma, tab or semicolon) from the header of input files
+    pub auto_delimiter: bool,
+    // Format of the report written by `Engine::serialize_report_stdout`
+    pub report_format: ReportFormat,
+    // When fields of a csv report are quoted
+    pub report_quote_style: QuoteStyle,
+    // What happens to the part of balances below `decimal_places` in the report
+    pub report_dust: DustHandling,
+    // Fail processing if any account ends up with negative held funds
+    pub verify_no_negative_held: bool,
+    // Report rows in descending order of this column instead of insertion order
+    pub report_sort: Option<SortColumn>,
+    // Print at most that many errors of each type, the rest is summed up at the end
+    pub error_log_limit: Option<usize>,
+    // Read csv input files twice, to tell references to later transactions from
+    // references to nonexistent ones
+    pub prescan_transaction_ids: bool,
+    // Start csv reports with a `# transponster vX generated at <time>` line
+    pub report_metadata: bool,
+    // Drop the transaction history of accounts locked for that many records, keeping
+    // only their balances
+    pub evict_locked_after: Option<usize>,
+    // Accept csv amounts with a leading currency symbol and thousands separators,
+    // e.g. `$1,000.50`. Ndjson input fails with it.
+    pub lenient_amounts: bool,
+}
+
+impl EngineConfig {
+    // JSON for `.json` files, TOML otherwise
+    pub fn from_file(path: &Path) -> Result<Self, EngineError> {
+        let content = std::fs::read_to_string(path)?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        if is_json {
+            serde_json::from_str(&content).map_err(|e| EngineError::Config(e.to_string()))
+        } else {
+            toml::from_str(&content).map_err(|e| EngineError::Config(e.to_string()))
+        }
+    }
+
+    pub fn is_client_included(&self, client_id: ClientId) -> bool {
+        self.only_clients
+            .as_ref()
+            .is_none_or(|only| only.contains(&client_id))
+            && !self
+                .exclude_clients
+                .as_ref()
+                .is_some_and(|excluded| excluded.contains(&client_id))
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            decimal_places: 4,
+            strict: false,
+            store_withdrawals_for_dispute: true,
+            amounts_in_minor_units: false,
+            log_format: LogFormat::default(),
+            check_chronology: false,
+            skip_bad_rows: false,
+            only_clients: None,
+            exclude_clients: None,
+            balance_warning_threshold: None,
+            max_account_balance: None,
+            progress_interval: None,
+            verbose: false,
+            report_totals: false,
+            reject_zero_amount: false,
+            decimal_locale: DecimalLocale::default(),
+            warn_unbacked_disputes: false,
+            report_flush_interval: None,
+            lenient_resolves: false,
+            clamp_available_on_dispute: false,
+            normalize_amounts: true,
+            max_disputes_per_transaction: None,
+            record_events: false,
+            quiet: false,
+            detailed_report: false,
+            record_limit: None,
+            limit_counts_malformed: true,
+            reject_dispute_amounts: false,
+            dedupe_per_operation: false,
+            reuse_settled_ids: false,
+            dedupe_fingerprints: false,
+            input_format: InputFormat::Csv,
+            auto_delimiter: false,
+            report_format: ReportFormat::Csv,
+            report_quote_style: QuoteStyle::Necessary,
+            report_dust: DustHandling::Round,
+            verify_no_negative_held: false,
+            report_sort: None,
+            error_log_limit: None,
+            prescan_transaction_ids: false,
+            report_metadata: false,
+            evict_locked_after: None,
+            lenient_amounts: false,
+        }
+    }
+}
+
+// Format of processing errors written to the error log (stderr by default)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+// Format of the separate error file set with `Engine::set_error_log`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorLogFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+// `Ndjson` input has one JSON transaction per line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+// `Bincode` reports are length prefixed binary records, see `report::BinaryReportSink`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    Bincode,
+}
+
+// Report column rows can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SortColumn {
+    Total,
+    Available,
+}
+
+impl SortColumn {
+    pub fn value(&self, row: &ReportRow) -> Decimal {
+        match self {
+            SortColumn::Total => row.total,
+            SortColumn::Available => row.available,
+        }
+    }
+}
+
+// Reported balances have at most `decimal_places`. `Round` rounds each of them, so
+// available plus held can differ from the total. `Drop` truncates available and held
+// and reports their sum as the total, `Sweep` additionally puts the truncated rest of
+// the stored total into a `dust` column, so every row adds up to the stored values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DustHandling {
+    #[default]
+    Round,
+    Drop,
+    Sweep,
+}
+
+// Quoting of csv report fields, `Necessary` quotes only fields containing a delimiter,
+// quote or line break. `Never` can produce a report that can't be read back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteStyle {
+    Always,
+    #[default]
+    Necessary,
+    Never,
+}
+
+impl From<QuoteStyle> for csv::QuoteStyle {
+    fn from(style: QuoteStyle) -> Self {
+        match style {
+            QuoteStyle::Always => csv::QuoteStyle::Always,
+            QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            QuoteStyle::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+// `Comma` amounts look like `1.234,56`, with `.` or space as thousands separator
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DecimalLocale {
+    #[default]
+    Dot,
+    Comma,
+}
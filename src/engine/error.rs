@@ -1,12 +1,33 @@
+use serde::Serialize;
+use strum::IntoStaticStr;
 use thiserror::Error;
 
+use std::fmt;
+
+use rust_decimal::Decimal;
+
 use super::models::{ClientId, OperationType, TransactionId};
+
 #[derive(Error, Debug)]
 pub enum EngineError {
     #[error("Parsing error")]
     Parsing(#[from] csv::Error),
+    #[error("JSON parsing error")]
+    JsonParsing(#[from] serde_json::Error),
+    #[error("Binary report error")]
+    Binary(#[from] bincode::Error),
+    #[error("Archive error")]
+    Archive(#[from] zip::result::ZipError),
     #[error("IO read error")]
     Reading(#[from] std::io::Error),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error("Account `{0}` holds negative funds `{1}`")]
+    NegativeHeld(ClientId, Decimal),
+    #[error("Transaction `{1}` of account `{0}` differs between the merged engines")]
+    MergeConflict(ClientId, TransactionId),
+    #[error("Account `{0}` has different currencies in the merged engines")]
+    CurrencyConflict(ClientId),
 
     // This one is not returned, just printed to stderr
     // so we don't break the transaction processing
@@ -15,35 +36,77 @@ pub enum EngineError {
 }
 
 // This error is only for internal error reporting to stderr
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Error, Debug, PartialEq, Eq, IntoStaticStr)]
 pub enum ProcessingError {
     #[error("Negative amount")]
     NegativeAmount,
 
-    #[error("Value overflow detected for transaction id `{0}`")]
-    Overflow(TransactionId),
+    #[error("Zero amount in transaction `{0}`")]
+    ZeroAmount(TransactionId),
+
+    #[error("Amount in transaction `{0}` is not a whole number of minor units")]
+    FractionalMinorUnits(TransactionId),
+
+    #[error("Amount in transaction `{0}` has more than `{1}` decimal places")]
+    ExcessivePrecision(TransactionId, u32),
+
+    // Without a transaction when a sum computed for the report overflows
+    #[error("Value overflow detected{}", for_transaction(.0))]
+    Overflow(Option<TransactionId>),
+
+    #[error("Balance of account `{0}` in the merged report can't be represented")]
+    UnrepresentableBalance(ClientId),
 
     #[error("Value underflow detected for transaction id `{0}`")]
     Underflow(TransactionId),
 
+    #[error("Held funds would become negative for transaction id `{0}`")]
+    HeldUnderflow(TransactionId),
+
     #[error("Duplicated transaction `{0}` for account `{1}`")]
     DuplicatedTransaction(TransactionId, ClientId),
 
     #[error("Duplicated dispute for transaction `{0}`, by transaction `{0}` for account `{1}`")]
     DuplicatedDispute(TransactionId, TransactionId, ClientId),
 
+    #[error("Transaction `{0}` was already disputed the maximum of `{1}` times")]
+    DisputeLimitExceeded(TransactionId, u32),
+
+    #[error("Transaction `{0}` repeats an already applied row")]
+    DuplicateFingerprint(TransactionId),
+
+    #[error("Transaction `{0}` carries an amount, but its type takes none")]
+    UnexpectedAmount(TransactionId),
+
+    #[error("Transaction `{0}` rejected by hook: {1}")]
+    RejectedByHook(TransactionId, String),
+
     #[error("Account `{0}` is locked")]
     AccountLocked(ClientId),
 
     #[error("No amount in transaction `{0}`")]
     MissingAmount(TransactionId),
 
+    // Stored deposits and withdrawals always have an amount, so this is an internal bug
+    #[error("Stored amount of disputed transaction `{0}` is missing")]
+    CorruptDisputedAmount(TransactionId),
+
+    #[error("Deposit would take the total of account `{0}` over the configured maximum")]
+    BalanceCapExceeded(ClientId),
+
+    // Held funds of open withdrawal disputes can't exceed what was ever withdrawn
+    #[error("Dispute of withdrawal `{0}` would hold more than the account ever withdrew")]
+    WithdrawalDisputeExceedsWithdrawn(TransactionId),
+
     #[error("insufficient founds for transaction `{0}`; account: `{1}`")]
     InsufficientFounds(TransactionId, ClientId),
 
     #[error("Referenced transaction `{0}` doesn't exist")]
     MissingTransaction(TransactionId),
 
+    #[error("Referenced transaction `{0}` comes later in the input")]
+    DisputeBeforeTransaction(TransactionId),
+
     #[error("Invalid operation `{0}` under dispute for transaction `{1}`")]
     InvalidOperationUnderDispute(OperationType, TransactionId),
 
@@ -52,4 +115,159 @@ pub enum ProcessingError {
 
     #[error("Chargeback called on not disputed operation `{0}` for transaction `{1}`")]
     IncorrectChargeback(OperationType, TransactionId),
+
+    #[error("Chargeback of transaction `{0}` exceeds the disputed amount `{1}`")]
+    ExcessiveChargeback(TransactionId, Decimal),
+
+    #[error("Transaction `{0}` is older than a previously processed one")]
+    OutOfOrder(TransactionId),
+
+    #[error("Invalid client id in row `{0}`")]
+    InvalidClient(String),
+
+    #[error("Currency `{1}` of transaction `{0}` differs from account currency `{2}`")]
+    CurrencyMismatch(TransactionId, String, String),
+}
+
+// Inconsistencies in account state found by `Engine::verify_invariants`
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InvariantViolation {
+    #[error("Account `{client_id}` holds `{held}` but disputed transactions sum to `{expected}`")]
+    HeldMismatch {
+        client_id: ClientId,
+        held: Decimal,
+        expected: Decimal,
+    },
+
+    #[error("Disputed transaction `{1}` of account `{0}` is not stored")]
+    MissingDisputedTransaction(ClientId, TransactionId),
+}
+
+// Findings that don't stop a transaction from being applied
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Warning {
+    #[error(
+        "Available balance `{available}` of account `{client_id}` exceeds the warning threshold"
+    )]
+    BalanceThresholdExceeded {
+        client_id: ClientId,
+        available: Decimal,
+    },
+
+    #[error(
+        "Disputed withdrawal `{tx}` leaves total `{total}` of account `{client_id}` above its net deposits `{net_deposits}`"
+    )]
+    UnbackedWithdrawalDispute {
+        client_id: ClientId,
+        tx: TransactionId,
+        total: Decimal,
+        net_deposits: Decimal,
+    },
+
+    #[error(
+        "Dispute of transaction `{tx}` of account `{client_id}` holds `{shortfall}` less than disputed, available funds ran out"
+    )]
+    PartialHold {
+        client_id: ClientId,
+        tx: TransactionId,
+        shortfall: Decimal,
+    },
+
+    #[error(
+        "Total `{total}` of account `{client_id}` doesn't match `{expected}` expected from its deposits, withdrawals and disputes"
+    )]
+    ReconciliationMismatch {
+        client_id: ClientId,
+        total: Decimal,
+        expected: Decimal,
+    },
+}
+
+impl ProcessingError {
+    // Variant name, stable identifier for log aggregation
+    pub fn kind(&self) -> &'static str {
+        self.into()
+    }
+}
+
+fn for_transaction(id: &Option<TransactionId>) -> String {
+    id.map(|id| format!(" for transaction id `{id}`"))
+        .unwrap_or_default()
+}
+
+// Structured form of a logged error, used for JSON logs
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorLogEntry {
+    #[serde(skip)]
+    pub category: &'static str,
+    pub error_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientId>,
+    pub message: String,
+}
+
+// Entries know where the error happened (line, tx and client where available)
+pub type LocatedError = ErrorLogEntry;
+
+impl ErrorLogEntry {
+    pub fn processing(
+        error: &ProcessingError,
+        line: Option<u64>,
+        tx: TransactionId,
+        client: ClientId,
+    ) -> Self {
+        Self {
+            category: "Processing",
+            error_type: error.kind(),
+            line,
+            tx: Some(tx),
+            client: Some(client),
+            message: error.to_string(),
+        }
+    }
+
+    // For rows that can't be attributed to a transaction or a client
+    pub fn unattributed(error: &ProcessingError, line: Option<u64>) -> Self {
+        Self {
+            category: "Processing",
+            error_type: error.kind(),
+            line,
+            tx: None,
+            client: None,
+            message: error.to_string(),
+        }
+    }
+
+    pub fn parsing(error: &csv::Error) -> Self {
+        Self {
+            category: "Parsing",
+            error_type: "Parsing",
+            line: error.position().map(|position| position.line()),
+            tx: None,
+            client: None,
+            message: error.to_string(),
+        }
+    }
+
+    // serde_json positions are relative to the line, so the input line is passed in
+    pub fn json_parsing(error: &serde_json::Error, line: u64) -> Self {
+        Self {
+            category: "Parsing",
+            error_type: "Parsing",
+            line: Some(line),
+            tx: None,
+            client: None,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {}", self.category, self.message)
+    }
 }
@@ -0,0 +1,124 @@
+use rust_decimal::Decimal;
+
+use super::models::{ClientId, OperationType, Transaction, TransactionId};
+
+// Deterministic generator of internally consistent transaction sequences, for
+// benchmarks and tests. Withdrawals never exceed what is available, and every
+// dispute references an existing deposit that is later resolved (never charged
+// back, so accounts stay unlocked and every row applies cleanly).
+pub fn generate_transactions(n: usize, seed: u64) -> Vec<Transaction> {
+    let mut rng = SplitMix64(seed);
+    let mut clients: Vec<ClientState> = Vec::new();
+    let mut transactions = Vec::with_capacity(n);
+    let mut next_id: TransactionId = 1;
+
+    while transactions.len() < n {
+        // New clients show up until there is a reasonable pool of them
+        let index = if clients.is_empty() || (clients.len() < 100 && rng.below(10) == 0) {
+            clients.push(ClientState::new(clients.len() as ClientId + 1));
+            clients.len() - 1
+        } else {
+            rng.below(clients.len() as u64) as usize
+        };
+        let client = &mut clients[index];
+
+        let transaction = match rng.below(10) {
+            0 if !client.disputed.is_empty() => client.resolve(&mut rng),
+            1 if !client.deposits.is_empty() => client.dispute(&mut rng),
+            2..=4 if client.available > Decimal::ZERO => client.withdraw(&mut rng, next_id),
+            _ => client.deposit(&mut rng, next_id),
+        };
+
+        if matches!(
+            transaction.operation,
+            OperationType::Deposit | OperationType::Withdrawal
+        ) {
+            next_id += 1;
+        }
+
+        transactions.push(transaction);
+    }
+
+    transactions
+}
+
+struct ClientState {
+    id: ClientId,
+    available: Decimal,
+    // Undisputed deposits that can be disputed
+    deposits: Vec<(TransactionId, Decimal)>,
+    disputed: Vec<(TransactionId, Decimal)>,
+}
+
+impl ClientState {
+    fn new(id: ClientId) -> Self {
+        Self {
+            id,
+            available: Decimal::ZERO,
+            deposits: Vec::new(),
+            disputed: Vec::new(),
+        }
+    }
+
+    fn deposit(&mut self, rng: &mut SplitMix64, id: TransactionId) -> Transaction {
+        let amount = Decimal::new(rng.below(1_000_000) as i64 + 1, 4);
+        self.available += amount;
+        self.deposits.push((id, amount));
+        self.transaction(OperationType::Deposit, id, Some(amount))
+    }
+
+    fn withdraw(&mut self, rng: &mut SplitMix64, id: TransactionId) -> Transaction {
+        let amount = Decimal::new(rng.below(10_000) as i64 + 1, 4).min(self.available);
+        self.available -= amount;
+        self.transaction(OperationType::Withdrawal, id, Some(amount))
+    }
+
+    fn dispute(&mut self, rng: &mut SplitMix64) -> Transaction {
+        let index = rng.below(self.deposits.len() as u64) as usize;
+        let (id, amount) = self.deposits.swap_remove(index);
+        self.available -= amount;
+        self.disputed.push((id, amount));
+        self.transaction(OperationType::Dispute, id, None)
+    }
+
+    fn resolve(&mut self, rng: &mut SplitMix64) -> Transaction {
+        let index = rng.below(self.disputed.len() as u64) as usize;
+        let (id, amount) = self.disputed.swap_remove(index);
+        self.available += amount;
+        self.deposits.push((id, amount));
+        self.transaction(OperationType::Resolve, id, None)
+    }
+
+    fn transaction(
+        &self,
+        operation: OperationType,
+        id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction {
+            operation,
+            client_id: self.id,
+            id,
+            amount,
+            timestamp: None,
+            currency: None,
+        }
+    }
+}
+
+// Small self-contained PRNG, so generated data doesn't depend on any crate version
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
@@ -1,963 +1,5948 @@
 use anyhow::Result;
-use csv::{Reader, ReaderBuilder, Trim, Writer};
-use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim, Writer, WriterBuilder};
+use indexmap::IndexMap;
+use rust_decimal::{Decimal, RoundingStrategy};
+use zip::ZipArchive;
 
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod amount;
+use amount::Amount;
+
+pub mod config;
+use config::{
+    DecimalLocale, DustHandling, EngineConfig, ErrorLogFormat, InputFormat, LogFormat, ReportFormat,
+};
 
 pub mod error;
-use error::{EngineError, ProcessingError};
+use error::{
+    EngineError, ErrorLogEntry, InvariantViolation, LocatedError, ProcessingError, Warning,
+};
+
+pub mod generator;
 
 pub mod models;
-use models::{AccountData, AccountsMap, OperationType, ReportRow, Transaction};
+use models::{
+    AccountData, AccountEvent, AccountsMap, ClientId, LifetimeTotals, MisplacedDot, OperationType,
+    ProcessOutcome, ProcessingStats, ReferenceCounts, ReportRow, ReportTotals, Transaction,
+    TransactionId,
+};
+
+pub mod report;
+use report::{read_report, BinaryReportSink, CsvReportSink, ReportSink};
+
+pub mod store;
+use store::AccountStore;
+
+// Number of minor units in one unit when amounts_in_minor_units is set
+const MINOR_UNITS_PER_UNIT: i64 = 10_000;
+
+// Identifies a row regardless of its position in the input, `1.0` and `1.00` are equal
+type Fingerprint<A> = (OperationType, ClientId, TransactionId, Option<A>);
+
+// Called with the transaction and the resulting account state
+pub type AppliedCallback<A = Decimal> = Box<dyn FnMut(&Transaction<A>, &AccountData<A>)>;
 
-pub struct Engine {
-    accounts: AccountsMap,
+// Called with the transaction and the account state before it's applied
+pub type PreHook<A = Decimal> = Box<dyn FnMut(&Transaction<A>, &AccountData<A>) -> HookDecision>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookDecision {
+    Allow,
+    // The reason ends up in the `RejectedByHook` error
+    Reject(String),
+}
+
+// Generic over the amount representation, `Decimal` unless created with
+// `with_amount_config`, and over the account storage, in memory unless created with
+// `with_store`
+pub struct Engine<A: Amount = Decimal, S: AccountStore<A> = AccountsMap<A>> {
+    config: EngineConfig,
+    accounts: S,
+    stats: ProcessingStats,
+    // Highest id of a deposit or withdrawal, whether it was applied or not
+    max_transaction_id: Option<TransactionId>,
+    last_timestamp: Option<DateTime<Utc>>,
+    warnings: Vec<Warning>,
+    on_applied: Option<AppliedCallback<A>>,
+    pre_hook: Option<PreHook<A>>,
+    error_sink: Box<dyn Write>,
+    replay_log: Option<Writer<Box<dyn Write>>>,
+    error_log: Option<ErrorLog>,
+    trace: Option<Box<dyn Write>>,
+    // Errors are collected instead of logged while `run` is in progress
+    collected_errors: Option<Vec<LocatedError>>,
+    // Logged errors by `error_type`
+    error_counts: BTreeMap<&'static str, usize>,
+    // Accounts locked while processing, with the number of records read at that point,
+    // oldest first. Only kept with `evict_locked_after`.
+    locked_at: VecDeque<(usize, ClientId)>,
+    // Deposit and withdrawal ids not read yet with their client, only with
+    // `prescan_transaction_ids`
+    upcoming_ids: HashSet<(ClientId, TransactionId)>,
+    // Errors not printed because of `error_log_limit` since the last tally
+    suppressed_errors: BTreeMap<&'static str, usize>,
+    // Applied rows, only kept with `dedupe_fingerprints`
+    fingerprints: HashSet<Fingerprint<A>>,
+    // Set from outside (e.g. a signal handler) to stop reading further records
+    stop_flag: Option<Arc<AtomicBool>>,
+    // Records that could be parsed, for `record_limit`
+    parsed: usize,
+    // Records read when progress was last printed
+    progress_logged: Option<usize>,
+    // Byte offset in the csv input after the last record read, and where the
+    // current stream starts in the file when resuming
+    input_offset: u64,
+    offset_base: u64,
+}
+
+// The default in-memory store, so a bare `Engine::default()` infers it
+impl<A: Amount> Default for Engine<A> {
+    fn default() -> Self {
+        Self::with_amount_config(EngineConfig::default())
+    }
 }
 
 impl Engine {
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        Self::with_amount_config(config)
+    }
+}
+
+impl<A: Amount, S: AccountStore<A>> Engine<A, S> {
+    // For other amount types than `Decimal`, e.g. `Engine::<MinorUnits>::with_amount_config`
+    pub fn with_amount_config(config: EngineConfig) -> Self
+    where
+        S: Default,
+    {
+        Self::with_store(config, S::default())
+    }
+
+    // Accounts are kept in `store`, which should be empty
+    pub fn with_store(config: EngineConfig, store: S) -> Self {
         Self {
-            accounts: AccountsMap::new(),
+            config,
+            accounts: store,
+            stats: ProcessingStats::default(),
+            max_transaction_id: None,
+            last_timestamp: None,
+            warnings: Vec::new(),
+            on_applied: None,
+            pre_hook: None,
+            error_sink: Box::new(std::io::stderr()),
+            replay_log: None,
+            error_log: None,
+            trace: None,
+            collected_errors: None,
+            error_counts: BTreeMap::new(),
+            suppressed_errors: BTreeMap::new(),
+            upcoming_ids: HashSet::new(),
+            locked_at: VecDeque::new(),
+            fingerprints: HashSet::new(),
+            stop_flag: None,
+            parsed: 0,
+            progress_logged: None,
+            input_offset: 0,
+            offset_base: 0,
         }
     }
 
-    // This public method takes file to load.
-    pub fn process_input(&mut self, path: &PathBuf) -> Result<(), EngineError> {
-        let rdr = ReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .from_path(path)?;
-        self.process_from_reader(rdr)
+    // Replaces the destination of processing error logs (stderr by default)
+    pub fn set_error_sink(&mut self, sink: Box<dyn Write>) {
+        self.error_sink = sink;
     }
 
-    // This is extracted mostly for parsing test purposes but could also be used with other sources that just a file
-    pub fn process_from_reader<T: std::io::Read>(
+    // Every applied transaction is written there as csv, with amounts already converted
+    // from minor units. Processing the log with the same config, except for
+    // `amounts_in_minor_units` and `decimal_locale`, reproduces the report.
+    pub fn set_replay_log(&mut self, writer: Box<dyn Write>) {
+        self.replay_log = Some(csv::Writer::from_writer(writer));
+    }
+
+    // Every logged error is also written there, one row per error with its line number,
+    // regardless of `quiet` and `error_log_limit`. Keeps a full trail of skipped rows
+    // for reprocessing without mixing it into the report or the stderr log.
+    pub fn set_error_log(
         &mut self,
-        mut reader: Reader<T>,
+        writer: Box<dyn Write>,
+        format: ErrorLogFormat,
     ) -> Result<(), EngineError> {
-        for line in reader.deserialize() {
-            let transaction: Transaction = line?;
-
-            // That's how return processing error wrapped with EngineError
-            // This however stops the execution.
-            // self.process_one(transaction)?;
-
-            if let Err(e) = self.process_one(transaction) {
-                eprintln!("Processing error: {e}");
+        let log = match format {
+            ErrorLogFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(ERROR_LOG_HEADER)?;
+                ErrorLog::Csv(Box::new(writer))
             }
-        }
-
+            ErrorLogFormat::Ndjson => ErrorLog::Ndjson(writer),
+        };
+        self.error_log = Some(log);
         Ok(())
     }
 
-    pub fn serialize_report_to_writer<T: std::io::Write>(
-        &self,
-        mut writer: Writer<T>,
-    ) -> Result<(), EngineError> {
-        self.accounts
-            .iter()
-            .map(|(client_id, data)| ReportRow {
-                client_id: *client_id,
-                available: data.available,
-                held: data.held,
-                total: data.available + data.held,
-                locked: data.locked,
-            })
-            .try_for_each(|row| writer.serialize(row))?;
+    // One line per record read with its outcome: `<record>,<line>,<result>`, where the
+    // record is counted from 1, the line is empty if unknown (e.g. `process_iter`) and
+    // the result is `applied`, `filtered` or `skipped:<error type>`. Traces of two runs
+    // can be diffed record by record.
+    pub fn set_trace(&mut self, writer: Box<dyn Write>) {
+        self.trace = Some(writer);
+    }
 
-        writer.flush()?;
+    // Once the flag is set, processing stops before the next record. Everything
+    // applied so far stays, so the report covers the records read until then.
+    pub fn set_stop_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.stop_flag = Some(flag);
+    }
 
-        Ok(())
+    pub fn is_stopped(&self) -> bool {
+        self.stop_flag
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
     }
 
-    pub fn serialize_report_stdout(&mut self) -> Result<(), EngineError> {
-        let writer = csv::Writer::from_writer(std::io::stdout());
-        self.serialize_report_to_writer(writer)
+    // Where `process_input_from_offset` can continue after the records read so far
+    pub fn input_offset(&self) -> u64 {
+        self.input_offset
     }
-    fn process_one(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
-        let account = self.accounts.entry(transaction.client_id).or_default();
 
-        if account.locked {
-            return Err(ProcessingError::AccountLocked(transaction.client_id));
+    // Records are read until `record_limit` of them are counted
+    fn limit_reached(&self) -> bool {
+        let counted = match self.config.limit_counts_malformed {
+            true => self.stats.read,
+            false => self.parsed,
         };
-
-        match transaction.operation {
-            OperationType::Deposit => operation_deposit(account, transaction)?,
-            OperationType::Withdrawal => operation_withdraw(account, transaction)?,
-            OperationType::Dispute => operation_dispute(account, transaction)?,
-            OperationType::Resolve => operation_resolve(account, transaction)?,
-            OperationType::Chargeback => operation_chargeback(account, transaction)?,
-        }
-
-        Ok(())
+        self.config
+            .record_limit
+            .is_some_and(|limit| counted >= limit)
     }
-}
 
-fn operation_deposit(
-    account: &mut AccountData,
-    transaction: Transaction,
-) -> Result<(), ProcessingError> {
-    // Deduplication
-    if account.transactions.contains_key(&transaction.id) {
-        return Err(ProcessingError::DuplicatedTransaction(
-            transaction.id,
-            transaction.client_id,
-        ));
+    // Non-blocking findings collected during processing
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
     }
 
-    let amount = transaction
-        .amount
-        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
-
-    if amount < Decimal::ZERO {
-        return Err(ProcessingError::NegativeAmount);
+    pub fn max_transaction_id(&self) -> Option<TransactionId> {
+        self.max_transaction_id
     }
 
-    account.available = account
-        .available
-        .checked_add(amount)
-        .ok_or(ProcessingError::Overflow(transaction.id))?;
-
-    account.transactions.insert(transaction.id, transaction);
+    // Ranges of ids up to the highest one that no account has applied, for
+    // sequentially numbered inputs they point at lost or rejected rows
+    pub fn missing_transaction_ids(&self) -> Vec<RangeInclusive<TransactionId>> {
+        let Some(max) = self.max_transaction_id else {
+            return Vec::new();
+        };
 
-    Ok(())
-}
+        let mut ids: Vec<TransactionId> = self
+            .accounts
+            .iter()
+            .flat_map(|(_, account)| {
+                account
+                    .transactions
+                    .keys()
+                    .chain(account.withdrawal_ids.iter())
+            })
+            .copied()
+            .collect();
+        ids.sort_unstable();
 
-fn operation_withdraw(
-    account: &mut AccountData,
-    transaction: Transaction,
-) -> Result<(), ProcessingError> {
-    // Deduplication
-    if account.transactions.contains_key(&transaction.id) {
-        return Err(ProcessingError::DuplicatedTransaction(
-            transaction.id,
-            transaction.client_id,
-        ));
+        let mut missing = Vec::new();
+        let mut next = 1;
+        for id in ids
+            .into_iter()
+            .chain(std::iter::once(max.saturating_add(1)))
+        {
+            if id > next {
+                missing.push(next..=id - 1);
+            }
+            next = next.max(id.saturating_add(1));
+        }
+        missing
     }
 
-    let amount = transaction
-        .amount
-        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
-
-    if amount < Decimal::ZERO {
-        return Err(ProcessingError::NegativeAmount);
+    // Stored deposits and withdrawals of a client in the order they were applied
+    pub fn transactions_for(&self, client_id: ClientId) -> Option<Vec<&Transaction<A>>> {
+        self.accounts
+            .get(client_id)
+            .map(|account| account.transactions.values().collect())
     }
 
-    if account.available < amount {
-        return Err(ProcessingError::InsufficientFounds(
-            transaction.id,
-            transaction.client_id,
-        ));
+    // Ids of locked accounts in ascending order, without building the whole report
+    pub fn locked_clients(&self) -> Vec<ClientId> {
+        let mut locked: Vec<ClientId> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| account.locked)
+            .map(|(client_id, _)| client_id)
+            .collect();
+        locked.sort_unstable();
+        locked
     }
 
-    account.available = account
-        .available
-        .checked_sub(amount)
-        .ok_or(ProcessingError::Underflow(transaction.id))?;
+    // Counters in the Prometheus text exposition format
+    pub fn metrics_text(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, usize)>| {
+            let _ = writeln!(text, "# HELP transponster_{name} {help}");
+            let _ = writeln!(text, "# TYPE transponster_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "transponster_{name}{labels} {value}");
+            }
+        };
 
-    account.transactions.insert(transaction.id, transaction);
+        let unlabeled = |value| vec![(String::new(), value)];
+        metric(
+            "records_read_total",
+            "counter",
+            "Records read from the input.",
+            unlabeled(self.stats.read),
+        );
+        metric(
+            "records_applied_total",
+            "counter",
+            "Records applied to an account.",
+            unlabeled(self.stats.applied),
+        );
+        metric(
+            "records_skipped_total",
+            "counter",
+            "Records skipped because of an error.",
+            unlabeled(self.stats.skipped),
+        );
+        metric(
+            "records_filtered_total",
+            "counter",
+            "Records of clients excluded by the client filter.",
+            unlabeled(self.stats.filtered),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Logged errors by type.",
+            self.error_counts
+                .iter()
+                .map(|(error_type, count)| (format!("{{type=\"{error_type}\"}}"), *count))
+                .collect(),
+        );
+        metric(
+            "accounts",
+            "gauge",
+            "Known accounts.",
+            unlabeled(self.accounts.len()),
+        );
+        metric(
+            "locked_accounts",
+            "gauge",
+            "Locked accounts.",
+            unlabeled(self.locked_clients().len()),
+        );
 
-    Ok(())
-}
+        text
+    }
 
-fn operation_dispute(
-    account: &mut AccountData,
-    transaction: Transaction,
-) -> Result<(), ProcessingError> {
-    let referenced_transaction = account.transactions.get(&transaction.id);
+    // Deposits and withdrawals ever applied to an account, regardless of disputes.
+    // A merged report's balance counts as deposited.
+    pub fn lifetime_totals(&self, client_id: ClientId) -> Option<LifetimeTotals> {
+        self.accounts
+            .get(client_id)
+            .map(AccountData::lifetime_totals)
+    }
 
-    let disputed_transaction =
-        referenced_transaction.ok_or(ProcessingError::MissingTransaction(transaction.id))?;
+    // Data quality view: how many stored transactions were ever disputed. Withdrawals
+    // aren't stored (so not counted) when withdrawal disputes are disabled.
+    pub fn reference_counts(&self, client_id: ClientId) -> Option<ReferenceCounts> {
+        self.accounts
+            .get(client_id)
+            .map(AccountData::reference_counts)
+    }
 
-    // Check duplicated dispute for a transaction
-    if account.under_dispute.contains(&disputed_transaction.id) {
-        return Err(ProcessingError::DuplicatedDispute(
-            transaction.id,
-            disputed_transaction.id,
-            transaction.client_id,
-        ));
+    // Dispute events of an account in application order, empty unless `record_events` is set
+    pub fn account_events(&self, client_id: ClientId) -> Option<&[AccountEvent]> {
+        self.accounts
+            .get(client_id)
+            .map(|account| account.events.as_slice())
     }
 
-    let disputed_amount = disputed_transaction
-        .amount
-        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
+    // Administrative unlock, not reachable from the input. Open disputes are kept,
+    // since their amounts are still held, and so is the history `evict_locked_after`
+    // would drop. Returns false for unknown clients.
+    pub fn unlock_account(&mut self, client_id: ClientId) -> bool {
+        match self.accounts.get_mut(client_id) {
+            Some(account) => {
+                account.locked = false;
+                self.locked_at.retain(|&(_, locked)| locked != client_id);
+                true
+            }
+            None => false,
+        }
+    }
 
-    match disputed_transaction.operation {
-        OperationType::Deposit => {
-            // We need to do both checked operations to keep the transaction valid
-            let new_available = account
-                .available
-                .checked_sub(disputed_amount)
-                .ok_or(ProcessingError::Underflow(transaction.id))?;
+    // Integrity check of the dispute accounting: `held` of every account has to equal
+    // the sum of its currently disputed transactions. `total` isn't stored, the report
+    // computes it and fails with `Overflow` if it can't be represented.
+    pub fn verify_invariants(&self) -> Result<(), Vec<InvariantViolation>> {
+        let mut violations = Vec::new();
 
-            let new_held = account
-                .held
-                .checked_add(disputed_amount)
-                .ok_or(ProcessingError::Overflow(transaction.id))?;
+        for (client_id, account) in self.accounts.iter() {
+            let mut expected = account.opening_held;
+            for id in &account.under_dispute {
+                let held = account.partial_holds.get(id).copied();
+                match held.or_else(|| account.transactions.get(id).and_then(|t| t.amount)) {
+                    Some(amount) => expected = expected.saturating_add(amount),
+                    None => violations.push(InvariantViolation::MissingDisputedTransaction(
+                        client_id, *id,
+                    )),
+                }
+            }
 
-            account.available = new_available;
-            account.held = new_held;
-        }
-        OperationType::Withdrawal => {
-            // The other way around. I guess it means withdrawn money was
-            // not received, so we put it back for now
-            account.held = account
-                .held
-                .checked_add(disputed_amount)
-                .ok_or(ProcessingError::Overflow(transaction.id))?;
+            if expected != account.held {
+                violations.push(InvariantViolation::HeldMismatch {
+                    client_id,
+                    held: account.held.to_decimal(),
+                    expected: expected.to_decimal(),
+                });
+            }
         }
-        _ => {
-            return Err(ProcessingError::InvalidOperationUnderDispute(
-                transaction.operation,
-                transaction.id,
-            ))
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
         }
     }
 
-    account.under_dispute.insert(disputed_transaction.id);
-
-    Ok(())
-}
+    // Combines the state of an engine that processed another shard of the input.
+    // Balances and lifetime totals are added, histories, disputes and statistics are
+    // combined. A transaction id of an account used by both (or accounts in different
+    // currencies) is a conflict, and nothing is merged then. Even the same transaction
+    // would be counted twice, as both shards applied it. With `dedupe_per_operation` a
+    // deposit and a withdrawal can share an id. Accounts locked by the other engine are
+    // evicted as if its input followed ours. Upcoming ids aren't merged, they only exist while reading an input.
+    pub fn merge(&mut self, other: Engine<A, S>) -> Result<(), EngineError> {
+        for (client_id, theirs) in other.accounts.iter() {
+            let Some(ours) = self.accounts.get(client_id) else {
+                continue;
+            };
+            check_merge_conflicts(client_id, ours, theirs, &self.config)?;
+        }
 
-fn operation_resolve(
-    account: &mut AccountData,
-    transaction: Transaction,
-) -> Result<(), ProcessingError> {
-    let referenced_transaction = account.transactions.get(&transaction.id);
+        for (client_id, theirs) in other.accounts.into_accounts() {
+            match self.accounts.get_mut(client_id) {
+                Some(ours) => merge_account(ours, theirs),
+                None => {
+                    self.accounts.insert(client_id, theirs);
+                }
+            }
+        }
 
-    let disputed_transaction =
-        referenced_transaction.ok_or(ProcessingError::MissingTransaction(transaction.id))?;
+        let read = self.stats.read;
+        self.locked_at.extend(
+            other
+                .locked_at
+                .iter()
+                .map(|&(locked_at, client_id)| (read + locked_at, client_id)),
+        );
+        self.stats.read += other.stats.read;
+        self.stats.applied += other.stats.applied;
+        self.stats.skipped += other.stats.skipped;
+        self.stats.filtered += other.stats.filtered;
+        self.max_transaction_id = self.max_transaction_id.max(other.max_transaction_id);
+        self.warnings.extend(other.warnings);
+        for (error_type, count) in other.error_counts {
+            *self.error_counts.entry(error_type).or_default() += count;
+        }
+        self.fingerprints.extend(other.fingerprints);
 
-    // Check if transaction under dispute
-    if !account.under_dispute.contains(&disputed_transaction.id) {
-        return Err(ProcessingError::IncorrectResolve(
-            transaction.operation,
-            transaction.id,
-        ));
+        Ok(())
     }
 
-    let disputed_amount = disputed_transaction
-        .amount
-        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
+    // Cross-checks the total of every account with its lifetime flows: deposits minus
+    // withdrawals, plus disputed withdrawals (held without taking available funds) minus
+    // charged back amounts. A mismatch means a logic bug or a state changed from outside,
+    // every mismatched account gets a warning.
+    pub fn reconcile_accounts(&mut self) {
+        for (client_id, account) in self.accounts.iter() {
+            let total = account.available.saturating_add(account.held).to_decimal();
+            let expected = account
+                .lifetime_totals()
+                .net_position()
+                .saturating_add(account.total_disputed_withdrawals.to_decimal())
+                .saturating_sub(account.total_charged_back.to_decimal());
 
-    match disputed_transaction.operation {
-        OperationType::Deposit | OperationType::Withdrawal => {
-            let new_available = account
-                .available
-                .checked_add(disputed_amount)
-                .ok_or(ProcessingError::Overflow(transaction.id))?;
+            if total != expected {
+                self.warnings.push(Warning::ReconciliationMismatch {
+                    client_id,
+                    total,
+                    expected,
+                });
+            }
+        }
+    }
 
-            let new_held = account
-                .held
-                .checked_sub(disputed_amount)
-                .ok_or(ProcessingError::Underflow(transaction.id))?;
+    // Digest of all account states (balances, histories, disputes) for reproducibility
+    // audits: two runs over the same input with the same options have equal hashes.
+    // The serialized form has a deterministic order and FNV-1a, unlike the std
+    // hasher, is stable across Rust versions.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        // Serializing into the hasher can't fail
+        let accounts: IndexMap<_, _> = self.accounts.iter().collect();
+        let _ = serde_json::to_writer(&mut hasher, &accounts);
+        hasher.0
+    }
 
-            account.available = new_available;
-            account.held = new_held;
-        }
-        _ => {
-            return Err(ProcessingError::InvalidOperationUnderDispute(
-                transaction.operation,
-                transaction.id,
-            ))
+    // Negative held funds can only come from a bug in the dispute accounting, unlike
+    // negative available funds
+    pub fn check_no_negative_held(&self) -> Result<(), EngineError> {
+        match self
+            .accounts
+            .iter()
+            .find(|(_, account)| account.held < A::ZERO)
+        {
+            Some((client_id, account)) => Err(EngineError::NegativeHeld(
+                client_id,
+                account.held.to_decimal(),
+            )),
+            None => Ok(()),
         }
     }
 
-    account.under_dispute.remove(&disputed_transaction.id);
-
-    Ok(())
-}
+    // Registers a callback invoked after each successfully applied transaction
+    pub fn on_applied(&mut self, callback: AppliedCallback<A>) {
+        self.on_applied = Some(callback);
+    }
 
-fn operation_chargeback(
-    account: &mut AccountData,
-    transaction: Transaction,
-) -> Result<(), ProcessingError> {
-    let referenced_transaction = account.transactions.get(&transaction.id);
+    // Registers a hook that can veto deposits, withdrawals and dispute rows after the
+    // built-in validation, e.g. for custom risk rules. Queries don't reach it.
+    pub fn set_pre_hook(&mut self, hook: PreHook<A>) {
+        self.pre_hook = Some(hook);
+    }
 
-    let disputed_transaction =
-        referenced_transaction.ok_or(ProcessingError::MissingTransaction(transaction.id))?;
+    // Counters of records read, applied and skipped so far
+    pub fn stats(&self) -> ProcessingStats {
+        self.stats
+    }
 
-    // Check if transaction under dispute
-    if !account.under_dispute.contains(&disputed_transaction.id) {
-        return Err(ProcessingError::IncorrectChargeback(
-            transaction.operation,
-            transaction.id,
-        ));
+    // Loads balances of a previously written report as the starting state. Only balances
+    // and the locked flag carry forward, old transactions can't be disputed.
+    pub fn load_report(&mut self, path: &Path) -> Result<(), EngineError> {
+        let rdr = reader_builder().from_path(path)?;
+        self.load_report_from_reader(rdr)
     }
 
-    let disputed_amount = disputed_transaction
-        .amount
-        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
+    pub fn load_report_from_reader<T: std::io::Read>(
+        &mut self,
+        reader: Reader<T>,
+    ) -> Result<(), EngineError> {
+        for row in read_report(reader)? {
+            let unrepresentable = || ProcessingError::UnrepresentableBalance(row.client_id);
+            let available = A::from_decimal(row.available).ok_or_else(unrepresentable)?;
+            let held = A::from_decimal(row.held).ok_or_else(unrepresentable)?;
 
-    match disputed_transaction.operation {
-        OperationType::Deposit | OperationType::Withdrawal => {
-            account.held = account
-                .held
-                .checked_sub(disputed_amount)
-                .ok_or(ProcessingError::Underflow(transaction.id))?;
-        }
-        _ => {
-            return Err(ProcessingError::InvalidOperationUnderDispute(
-                transaction.operation,
-                transaction.id,
-            ))
+            self.accounts.insert(
+                row.client_id,
+                AccountData {
+                    available,
+                    held,
+                    locked: row.locked,
+                    opening_held: held,
+                    // Opening balance counts as deposited for the net deposits
+                    total_deposited: available.saturating_add(held),
+                    ..Default::default()
+                },
+            );
         }
+
+        Ok(())
     }
 
-    account.under_dispute.remove(&disputed_transaction.id);
+    // Side effect free variant of processing: errors and the report are returned
+    // instead of being written out. Only input that can't be read at all fails.
+    pub fn run<T: std::io::Read>(
+        &mut self,
+        reader: Reader<T>,
+    ) -> Result<ProcessOutcome, EngineError> {
+        self.collected_errors = Some(Vec::new());
+        let processed = self.process_from_reader(reader);
+        let errors = self.collected_errors.take().unwrap_or_default();
+        processed?;
 
-    account.locked = true;
+        Ok(ProcessOutcome {
+            rows: self.report_rows()?,
+            errors,
+            stats: self.stats,
+        })
+    }
 
-    Ok(())
-}
+    // `run` with the rows of the outcome written as the csv report, the same way
+    // `serialize_report_to_writer` writes them
+    pub fn run_to_writer<T: std::io::Read, W: std::io::Write>(
+        &mut self,
+        reader: Reader<T>,
+        writer: W,
+    ) -> Result<ProcessOutcome, EngineError> {
+        let outcome = self.run(reader)?;
+        let mut sink = self.csv_report_sink(writer)?;
+        self.emit_rows(outcome.rows.iter().cloned().map(Ok), &mut sink)?;
+        Ok(outcome)
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashSet;
+    // This public method takes file to load.
+    #[tracing::instrument(skip(self))]
+    pub fn process_input(&mut self, path: &PathBuf) -> Result<(), EngineError> {
+        let file = std::fs::File::open(path)?;
 
-    use rust_decimal_macros::dec;
+        let is_zip = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+        if is_zip {
+            return self.process_zip_from_reader(file);
+        }
 
-    use crate::engine::error::ProcessingError;
-    use crate::engine::models::AccountData;
-    use crate::engine::models::OperationType;
+        match self.config.input_format {
+            InputFormat::Csv if self.config.prescan_transaction_ids => {
+                let mut file = BufReader::new(file);
+                self.upcoming_ids = self.scan_transaction_ids(&mut file)?;
+                file.rewind()?;
+                let processed = self.process_csv(file);
+                self.upcoming_ids.clear();
+                processed
+            }
+            InputFormat::Csv => self.process_csv(BufReader::new(file)),
+            InputFormat::Ndjson => self.process_ndjson_from_reader(BufReader::new(file)),
+        }
+    }
 
-    use super::Transaction;
+    // Client and transaction ids of deposit and withdrawal rows, without applying
+    // anything. Unparsable rows are skipped, processing reports them.
+    fn scan_transaction_ids<R: BufRead>(
+        &self,
+        mut reader: R,
+    ) -> Result<HashSet<(ClientId, TransactionId)>, EngineError> {
+        let mut builder = reader_builder();
+        if self.config.auto_delimiter {
+            builder.delimiter(sniff_delimiter(reader.fill_buf()?));
+        }
+        let mut reader = builder.from_reader(reader);
 
-    #[test]
-    fn error_duplicated_transaction() {
-        let mut engine = super::Engine::new();
-        engine
-            .process_one(Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            })
-            .unwrap();
+        let headers: StringRecord = reader.headers()?.iter().map(str::to_lowercase).collect();
+        let column = |name| headers.iter().position(|header| header == name);
+        let (Some(type_index), Some(client_index), Some(tx_index)) =
+            (column("type"), column("client"), column("tx"))
+        else {
+            return Ok(HashSet::new());
+        };
 
-        let result = engine.process_one(Transaction {
-            id: 1,
-            operation: OperationType::Withdrawal,
-            client_id: 10,
-            amount: Some(dec!(2)),
-        });
+        let mut ids = HashSet::new();
+        let mut record = StringRecord::new();
+        while reader.read_record(&mut record)? {
+            let is_stored_type = record.get(type_index).is_some_and(|operation| {
+                operation.eq_ignore_ascii_case("deposit")
+                    || operation.eq_ignore_ascii_case("withdrawal")
+            });
+            if !is_stored_type {
+                continue;
+            }
+            let client_id = record.get(client_index).map(str::parse::<ClientId>);
+            let tx_id = record.get(tx_index).map(str::parse::<TransactionId>);
+            if let (Some(Ok(client_id)), Some(Ok(tx_id))) = (client_id, tx_id) {
+                ids.insert((client_id, tx_id));
+            }
+        }
 
-        assert_eq!(result, Err(ProcessingError::DuplicatedTransaction(1, 10)));
+        Ok(ids)
     }
 
-    #[test]
-    fn error_insufficient_founds() {
-        let mut engine = super::Engine::new();
-        engine
-            .process_one(Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            })
-            .unwrap();
+    // Continues a csv file from a byte offset, e.g. `input_offset` of an interrupted run
+    // whose state was kept. The header is still read from the start of the file. An
+    // offset inside a record skips to the next line, so quoted fields spanning lines
+    // can't be resumed from.
+    pub fn process_input_from_offset(
+        &mut self,
+        path: &Path,
+        offset: u64,
+    ) -> Result<(), EngineError> {
+        let mut file = BufReader::new(std::fs::File::open(path)?);
+        let mut header = Vec::new();
+        file.read_until(b'\n', &mut header)?;
+        let header_end = header.len() as u64;
 
-        let result = engine.process_one(Transaction {
-            id: 2,
-            operation: OperationType::Withdrawal,
-            client_id: 10,
-            amount: Some(dec!(2)),
-        });
+        if offset > header_end {
+            // The byte before a record boundary is a newline
+            file.seek(SeekFrom::Start(offset - 1))?;
+            file.read_until(b'\n', &mut Vec::new())?;
+        }
+        let start = file.stream_position()?;
 
-        assert_eq!(result, Err(ProcessingError::InsufficientFounds(2, 10)));
+        self.offset_base = start - header_end;
+        let processed = self.process_csv(BufReader::new(header.as_slice().chain(file)));
+        self.offset_base = 0;
+        processed
+    }
+
+    // Processes a csv file and then keeps polling it for appended records, until the stop
+    // flag is set or the record limit is reached. A trailing line without a newline is
+    // left for a later poll, since the writer may be in the middle of it. Line numbers
+    // in logged errors are relative to the records read in the same poll.
+    pub fn watch_input(&mut self, path: &Path, poll_interval: Duration) -> Result<(), EngineError> {
+        let mut file = std::fs::File::open(path)?;
+        let mut pending = Vec::new();
+        let mut header: Option<Vec<u8>> = None;
+        // Bytes of records processed so far, after the header
+        let mut consumed = 0;
+
+        loop {
+            file.read_to_end(&mut pending)?;
+
+            if header.is_none() {
+                if let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                    header = Some(pending.drain(..=end).collect());
+                }
+            }
+
+            if let Some(header) = &header {
+                if let Some(end) = pending.iter().rposition(|byte| *byte == b'\n') {
+                    let records: Vec<u8> = pending.drain(..=end).collect();
+                    self.offset_base = consumed;
+                    let processed = self.process_csv(header.as_slice().chain(records.as_slice()));
+                    self.offset_base = 0;
+                    processed?;
+                    consumed += records.len() as u64;
+                }
+            }
+
+            if self.is_stopped() || self.limit_reached() {
+                return Ok(());
+            }
+            std::thread::sleep(poll_interval);
+        }
+    }
+
+    // All `.csv` entries of the archive in name order, as if they were one input.
+    // Other entries are skipped.
+    pub fn process_zip_from_reader<R: Read + Seek>(
+        &mut self,
+        reader: R,
+    ) -> Result<(), EngineError> {
+        let mut archive = ZipArchive::new(reader)?;
+
+        let mut names: Vec<String> = archive
+            .file_names()
+            .filter(|name| {
+                Path::new(name)
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+            })
+            .map(String::from)
+            .collect();
+        names.sort_unstable();
+
+        // Over all entries, a dispute can reference a transaction of a later one
+        if self.config.prescan_transaction_ids {
+            for name in &names {
+                let ids = self.scan_transaction_ids(BufReader::new(archive.by_name(name)?))?;
+                self.upcoming_ids.extend(ids);
+            }
+        }
+
+        let processed = names.iter().try_for_each(|name| {
+            let entry = archive.by_name(name)?;
+            self.process_csv(BufReader::new(entry))
+        });
+        self.upcoming_ids.clear();
+        processed
+    }
+
+    fn process_csv<R: BufRead>(&mut self, mut reader: R) -> Result<(), EngineError> {
+        let mut builder = reader_builder();
+        if self.config.auto_delimiter {
+            // Peeked bytes stay in the buffer, the csv reader still gets the header
+            builder.delimiter(sniff_delimiter(reader.fill_buf()?));
+        }
+        self.process_from_reader(builder.from_reader(reader))
+    }
+
+    // For incrementally arriving data (e.g. a socket). Each record is applied as soon as
+    // it's complete, reads returning partial records just make the parser wait for more.
+    pub fn process_stream<R: BufRead>(&mut self, reader: R) -> Result<(), EngineError> {
+        self.process_from_reader(reader_builder().from_reader(reader))
+    }
+
+    // This is extracted mostly for parsing test purposes but could also be used with other sources that just a file
+    #[tracing::instrument(skip_all)]
+    pub fn process_from_reader<T: std::io::Read>(
+        &mut self,
+        mut reader: Reader<T>,
+    ) -> Result<(), EngineError> {
+        // Header names are matched case-insensitively
+        let headers: StringRecord = reader.headers()?.iter().map(str::to_lowercase).collect();
+
+        // Amounts in comma notation or with currency symbols are rewritten before parsing
+        let rewrite_amounts =
+            self.config.decimal_locale == DecimalLocale::Comma || self.config.lenient_amounts;
+        let amount_index = headers
+            .iter()
+            .position(|header| header == "amount")
+            .filter(|_| rewrite_amounts);
+        let client_index = headers.iter().position(|header| header == "client");
+
+        let mut record = StringRecord::new();
+        while !self.is_stopped() && !self.limit_reached() {
+            let mut invalid_client = false;
+            let mut parsed = match reader.read_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => {
+                    invalid_client = client_index.is_some_and(|index| {
+                        record
+                            .get(index)
+                            .is_none_or(|client| client.parse::<ClientId>().is_err())
+                    });
+                    let rewritten = amount_index.and_then(|index| {
+                        let amount = self.normalize_amount_text(record.get(index)?);
+                        Some((index, amount))
+                    });
+                    match rewritten {
+                        Some((index, Some(amount))) => {
+                            rewrite_field(&record, index, &amount)
+                                .deserialize::<Transaction<A>>(Some(&headers))
+                        }
+                        Some((_, None)) => record
+                            .deserialize::<MisplacedDot>(Some(&headers))
+                            .map(|rejected| match rejected.amount {}),
+                        None => record.deserialize::<Transaction<A>>(Some(&headers)),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            self.input_offset = self.offset_base + reader.position().byte();
+
+            // Broken input stream can't be skipped, a row with a malformed client id always can
+            if let Err(e) = parsed {
+                if e.is_io_error() || !(self.config.skip_bad_rows || invalid_client) {
+                    return Err(e.into());
+                }
+                parsed = Err(e);
+            }
+
+            self.count_read();
+
+            let transaction = match parsed {
+                Ok(transaction) => transaction,
+                Err(_) if invalid_client => {
+                    let raw = record.iter().collect::<Vec<_>>().join(",");
+                    let line = record.position().map(|position| position.line());
+                    self.skip(ErrorLogEntry::unattributed(
+                        &ProcessingError::InvalidClient(raw),
+                        line,
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    self.skip(ErrorLogEntry::parsing(&e));
+                    continue;
+                }
+            };
+
+            let line = record.position().map(|position| position.line());
+            self.apply_parsed(transaction, line)?;
+        }
+
+        self.finish_input()
+    }
+
+    // One JSON object per line, with the same field names as the csv header
+    // (`type`, `client`, `tx`, `amount`, ...). Amounts are strings, like in csv,
+    // so they are not rounded by a float conversion. Blank lines are ignored.
+    #[tracing::instrument(skip_all)]
+    pub fn process_ndjson_from_reader<R: BufRead>(&mut self, reader: R) -> Result<(), EngineError> {
+        // Amounts are only rewritten in csv records, ignoring the option would be silent
+        if self.config.lenient_amounts {
+            return Err(EngineError::Config(
+                "`lenient_amounts` is only supported for csv input".to_string(),
+            ));
+        }
+        for (index, line) in reader.lines().enumerate() {
+            if self.is_stopped() || self.limit_reached() {
+                break;
+            }
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let line_number = index as u64 + 1;
+            let transaction = match serde_json::from_str::<Transaction<A>>(&line) {
+                Ok(transaction) => transaction,
+                Err(e) if !self.config.skip_bad_rows => return Err(e.into()),
+                Err(e) => {
+                    self.count_read();
+                    self.skip(ErrorLogEntry::json_parsing(&e, line_number));
+                    continue;
+                }
+            };
+
+            self.count_read();
+            self.apply_parsed(transaction, Some(line_number))?;
+        }
+
+        self.finish_input()
+    }
+
+    // Already parsed transactions, e.g. received from another service, without a round
+    // trip through csv. Filtering, statistics and error logging are the same as for an
+    // input file, logged errors just have no line number.
+    pub fn process_iter<I: IntoIterator<Item = Transaction<A>>>(
+        &mut self,
+        transactions: I,
+    ) -> Result<(), EngineError> {
+        for transaction in transactions {
+            if self.is_stopped() || self.limit_reached() {
+                break;
+            }
+            self.count_read();
+            self.apply_parsed(transaction, None)?;
+        }
+
+        self.finish_input()
+    }
+
+    // A record read but not applied
+    fn skip(&mut self, entry: ErrorLogEntry) {
+        self.stats.skipped += 1;
+        self.trace(entry.line, &format!("skipped:{}", entry.error_type));
+        self.log(entry);
+    }
+
+    // Failing to write a trace line must not stop the processing
+    fn trace(&mut self, line: Option<u64>, result: &str) {
+        if let Some(trace) = self.trace.as_mut() {
+            let line = line.map(|line| line.to_string()).unwrap_or_default();
+            let _ = writeln!(trace, "{},{line},{result}", self.stats.read);
+        }
+    }
+
+    fn count_read(&mut self) {
+        self.stats.read += 1;
+        if self
+            .config
+            .progress_interval
+            .is_some_and(|interval| self.stats.read.is_multiple_of(interval))
+        {
+            self.log_progress();
+        }
+    }
+
+    // None if the amount is malformed for the locale
+    fn normalize_amount_text(&self, amount: &str) -> Option<String> {
+        let lenient = self.config.lenient_amounts;
+        let amount = match lenient {
+            true => strip_currency_symbol(amount),
+            false => amount,
+        };
+        match self.config.decimal_locale {
+            DecimalLocale::Dot if lenient => Some(strip_thousands_separators(amount)),
+            DecimalLocale::Dot => Some(amount.to_string()),
+            DecimalLocale::Comma => comma_amount_to_dot(amount),
+        }
+    }
+
+    // Filtering, processing and error reporting of a parsed row, whatever the input format
+    fn apply_parsed(
+        &mut self,
+        transaction: Transaction<A>,
+        line: Option<u64>,
+    ) -> Result<(), EngineError> {
+        self.parsed += 1;
+        if !self.config.is_client_included(transaction.client_id) {
+            self.stats.filtered += 1;
+            self.trace(line, "filtered");
+            return Ok(());
+        }
+
+        // That's how return processing error wrapped with EngineError
+        // This however stops the execution.
+        // self.process_one(transaction)?;
+
+        let (tx, client) = (transaction.id, transaction.client_id);
+        let was_locked = self
+            .accounts
+            .get(client)
+            .is_some_and(|account| account.locked);
+        if matches!(
+            transaction.operation,
+            OperationType::Deposit | OperationType::Withdrawal
+        ) {
+            self.upcoming_ids.remove(&(client, tx));
+        }
+
+        match self.process_one(transaction) {
+            Ok(()) => {
+                self.stats.applied += 1;
+                self.trace(line, "applied");
+            }
+            // The referenced transaction of the same client is further down the input
+            Err(ProcessingError::MissingTransaction(id))
+                if self.upcoming_ids.contains(&(client, id)) =>
+            {
+                let e = ProcessingError::DisputeBeforeTransaction(id);
+                self.skip(ErrorLogEntry::processing(&e, line, tx, client));
+            }
+            // Internal accounting bug, don't carry on with broken state in strict mode
+            Err(e @ ProcessingError::HeldUnderflow(_)) if self.config.strict => {
+                return Err(e.into())
+            }
+            Err(e) => {
+                self.skip(ErrorLogEntry::processing(&e, line, tx, client));
+            }
+        }
+
+        if let Some(after) = self.config.evict_locked_after {
+            if !was_locked && self.accounts.get(client).is_some_and(|a| a.locked) {
+                self.locked_at.push_back((self.stats.read, client));
+            }
+            self.evict_locked_accounts(after);
+        }
+
+        Ok(())
+    }
+
+    // Drops the history of accounts locked at least `after` records ago, balances stay.
+    // Funds still held have no known dispute anymore, so they become opening held funds.
+    fn evict_locked_accounts(&mut self, after: usize) {
+        while let Some(&(locked_at, client_id)) = self.locked_at.front() {
+            if self.stats.read - locked_at < after {
+                break;
+            }
+            self.locked_at.pop_front();
+
+            if let Some(account) = self.accounts.get_mut(client_id) {
+                account.opening_held = account.held;
+                // Replaced instead of cleared, so the memory is released
+                account.transactions = Default::default();
+                account.under_dispute = HashSet::new();
+                account.partial_holds.clear();
+                account.withdrawal_disputes_held = A::ZERO;
+            }
+        }
+    }
+
+    fn finish_input(&mut self) -> Result<(), EngineError> {
+        // Not repeated when the last periodic line already has the final count
+        if self.config.progress_interval.is_some() && self.progress_logged != Some(self.stats.read)
+        {
+            self.log_progress();
+        }
+
+        if let Some(log) = self.replay_log.as_mut() {
+            log.flush()?;
+        }
+        if let Some(log) = self.error_log.as_mut() {
+            log.flush()?;
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush()?;
+        }
+
+        self.log_suppressed_errors();
+
+        if self.config.verify_no_negative_held {
+            self.check_no_negative_held()?;
+        }
+
+        Ok(())
+    }
+
+    fn log_suppressed_errors(&mut self) {
+        for (error_type, suppressed) in std::mem::take(&mut self.suppressed_errors) {
+            if self.config.quiet {
+                continue;
+            }
+            tracing::warn!(error_type, suppressed, "errors suppressed by the log limit");
+
+            let _ = match self.config.log_format {
+                LogFormat::Plain => writeln!(
+                    self.error_sink,
+                    "Suppressed {suppressed} more `{error_type}` errors"
+                ),
+                LogFormat::Json => {
+                    let tally = serde_json::json!({
+                        "error_type": error_type,
+                        "suppressed": suppressed,
+                    });
+                    writeln!(self.error_sink, "{tally}")
+                }
+            };
+        }
+    }
+
+    fn log_progress(&mut self) {
+        // Neither `run` nor `quiet` print anything
+        if self.collected_errors.is_some() || self.config.quiet {
+            return;
+        }
+        let read = self.stats.read;
+        self.progress_logged = Some(read);
+
+        tracing::info!(read, "records processed");
+        let _ = match self.config.log_format {
+            LogFormat::Plain => writeln!(self.error_sink, "Processed {read} records"),
+            LogFormat::Json => {
+                let progress = serde_json::json!({ "processed": read });
+                writeln!(self.error_sink, "{progress}")
+            }
+        };
+    }
+
+    // Failing to write a log line must not stop the processing
+    fn log(&mut self, entry: ErrorLogEntry) {
+        if let Some(log) = self.error_log.as_mut() {
+            let _ = log.write(&entry);
+        }
+
+        let count = self.error_counts.entry(entry.error_type).or_default();
+        *count += 1;
+
+        // Past the limit errors are only counted, `finish_input` prints the tally
+        if self.collected_errors.is_none()
+            && self
+                .config
+                .error_log_limit
+                .is_some_and(|limit| *count > limit)
+        {
+            *self.suppressed_errors.entry(entry.error_type).or_default() += 1;
+            return;
+        }
+
+        if let Some(errors) = self.collected_errors.as_mut() {
+            errors.push(entry);
+            return;
+        }
+
+        if !self.config.quiet {
+            tracing::error!(
+                error_type = entry.error_type,
+                line = entry.line,
+                tx = entry.tx,
+                client = entry.client,
+                "{entry}"
+            );
+        }
+        if self.config.quiet {
+            return;
+        }
+
+        let _ = match self.config.log_format {
+            LogFormat::Plain => writeln!(self.error_sink, "{entry}"),
+            LogFormat::Json => serde_json::to_writer(&mut self.error_sink, &entry)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writeln!(self.error_sink)),
+        };
+    }
+
+    // Csv writer settings of the report, i.e. the configured quote style
+    fn report_writer_builder(&self) -> WriterBuilder {
+        let mut builder = WriterBuilder::new();
+        builder.quote_style(self.config.report_quote_style.into());
+        builder
+    }
+
+    pub fn serialize_report_to_writer<T: std::io::Write>(
+        &self,
+        writer: T,
+    ) -> Result<(), EngineError> {
+        let mut sink = self.csv_report_sink(writer)?;
+        self.emit_report(&mut sink)
+    }
+
+    fn csv_report_sink<T: std::io::Write>(
+        &self,
+        mut writer: T,
+    ) -> Result<CsvReportSink<T>, EngineError> {
+        // The csv writer can only write records, so the metadata line goes before it
+        if self.config.report_metadata {
+            writeln!(writer, "{}", metadata_line())?;
+        }
+
+        let writer = self.report_writer_builder().from_writer(writer);
+        Ok(CsvReportSink::new(writer)
+            .with_flush_interval(self.config.report_flush_interval)
+            .with_detailed(self.config.detailed_report)
+            .with_dust(self.config.report_dust == DustHandling::Sweep))
+    }
+
+    pub fn serialize_binary_report_to_writer<T: std::io::Write>(
+        &self,
+        writer: T,
+    ) -> Result<(), EngineError> {
+        self.emit_report(&mut BinaryReportSink::new(writer))
+    }
+
+    // Rows and totals are rounded to the configured precision before being emitted
+    pub fn emit_report<R: ReportSink + ?Sized>(&self, sink: &mut R) -> Result<(), EngineError> {
+        let dust_handling = self.config.report_dust;
+        let normalize = |value: Decimal| self.normalize_for_report(value);
+        let round = |value: Decimal| self.round_for_report(value);
+
+        let rows = self.accounts.iter().map(|(client_id, data)| {
+            // Both balances can be close to the limit, e.g. with a disputed withdrawal
+            let overflow = || ProcessingError::Overflow(None);
+            let total = data
+                .available
+                .checked_add(data.held)
+                .ok_or_else(overflow)?
+                .to_decimal();
+            let available = round(data.available.to_decimal());
+            let held = round(data.held.to_decimal());
+
+            // Truncated balances add up, what they leave out of the stored total is dust
+            let (total, dust) = match dust_handling {
+                DustHandling::Round => (round(total), None),
+                DustHandling::Drop | DustHandling::Sweep => {
+                    let shown = Amount::checked_add(available, held).ok_or_else(overflow)?;
+                    let dust = Amount::checked_sub(total, shown).ok_or_else(overflow)?;
+                    let dust = (dust_handling == DustHandling::Sweep).then(|| normalize(dust));
+                    (normalize(shown), dust)
+                }
+            };
+
+            Ok::<_, EngineError>(ReportRow {
+                client_id,
+                available,
+                held,
+                total,
+                locked: data.locked,
+                disputed: self
+                    .config
+                    .detailed_report
+                    .then_some(data.under_dispute.len()),
+                dust,
+            })
+        });
+
+        self.emit_rows(rows, sink)
+    }
+
+    // Sorting, totals footer and end of a report, whether its rows are computed from
+    // the accounts or come from the outcome of `run`
+    fn emit_rows<R: ReportSink + ?Sized>(
+        &self,
+        rows: impl Iterator<Item = Result<ReportRow, EngineError>>,
+        sink: &mut R,
+    ) -> Result<(), EngineError> {
+        let mut emitted = ReportTotals::default();
+        let mut emit = |row: &ReportRow| {
+            if self.config.report_totals {
+                add_to_totals(&mut emitted, row)?;
+            }
+            sink.emit(row)
+        };
+
+        match self.config.report_sort {
+            // Streamed, no need to keep all rows in memory
+            None => {
+                for row in rows {
+                    emit(&row?)?;
+                }
+            }
+            Some(column) => {
+                let mut rows = rows.collect::<Result<Vec<_>, _>>()?;
+                // Descending, lower client id first among equal values
+                rows.sort_by(|a, b| {
+                    column
+                        .value(b)
+                        .cmp(&column.value(a))
+                        .then(a.client_id.cmp(&b.client_id))
+                });
+                for row in &rows {
+                    emit(row)?;
+                }
+            }
+        }
+
+        if self.config.report_totals {
+            let totals = match self.config.report_dust {
+                DustHandling::Round => {
+                    let totals = self.totals()?;
+                    ReportTotals {
+                        available: self.round_for_report(totals.available),
+                        held: self.round_for_report(totals.held),
+                        total: self.round_for_report(totals.total),
+                        dust: None,
+                    }
+                }
+                // Sums of the truncated rows, so the footer adds up what they show. They
+                // are already truncated, this only normalizes them.
+                DustHandling::Drop | DustHandling::Sweep => ReportTotals {
+                    available: self.round_for_report(emitted.available),
+                    held: self.round_for_report(emitted.held),
+                    total: self.round_for_report(emitted.total),
+                    dust: emitted.dust.map(|dust| self.normalize_for_report(dust)),
+                },
+            };
+            sink.emit_totals(&totals)?;
+        }
+
+        sink.finish()
+    }
+
+    // Rounded to the configured precision, truncated unless dust is rounded. Sums can
+    // have trailing zeros even if all amounts were normalized, e.g. 0.5 + 0.5.
+    fn round_for_report(&self, value: Decimal) -> Decimal {
+        let strategy = match self.config.report_dust {
+            DustHandling::Round => RoundingStrategy::MidpointNearestEven,
+            DustHandling::Drop | DustHandling::Sweep => RoundingStrategy::ToZero,
+        };
+        self.normalize_for_report(
+            value.round_dp_with_strategy(self.config.decimal_places, strategy),
+        )
+    }
+
+    fn normalize_for_report(&self, value: Decimal) -> Decimal {
+        match self.config.normalize_amounts {
+            true => value.normalize(),
+            false => value,
+        }
+    }
+
+    // Report rows in memory, e.g. for comparing with an existing report
+    pub fn report_rows(&self) -> Result<Vec<ReportRow>, EngineError> {
+        let mut rows = Vec::new();
+        self.emit_report(&mut rows)?;
+        Ok(rows)
+    }
+
+    // Sums over all accounts fail instead of panicking once they outgrow a `Decimal`,
+    // which millions of large balances can do
+    pub fn totals(&self) -> Result<ReportTotals, EngineError> {
+        let add = |a: Decimal, b: Decimal| a.checked_add(b).ok_or(ProcessingError::Overflow(None));
+
+        self.accounts
+            .iter()
+            .try_fold(ReportTotals::default(), |totals, (_, data)| {
+                let (available, held) = (data.available.to_decimal(), data.held.to_decimal());
+                Ok(ReportTotals {
+                    available: add(totals.available, available)?,
+                    held: add(totals.held, held)?,
+                    total: add(add(totals.total, available)?, held)?,
+                    dust: None,
+                })
+            })
+    }
+
+    // Only reads the state, like the other report methods
+    pub fn serialize_report_stdout(&self) -> Result<(), EngineError> {
+        match self.config.report_format {
+            ReportFormat::Csv => self.serialize_report_to_writer(std::io::stdout()),
+            ReportFormat::Bincode => {
+                self.serialize_binary_report_to_writer(std::io::BufWriter::new(std::io::stdout()))
+            }
+        }
+    }
+
+    #[tracing::instrument(
+        level = "trace",
+        skip_all,
+        fields(tx = transaction.id, client = transaction.client_id)
+    )]
+    fn process_one(&mut self, transaction: Transaction<A>) -> Result<(), ProcessingError> {
+        // Transaction is consumed by the operation, so keep a copy only if somebody listens
+        let observed = self.on_applied.is_some().then(|| transaction.clone());
+        let replayed = self.replay_log.is_some().then(|| transaction.clone());
+        let client_id = transaction.client_id;
+
+        if matches!(
+            transaction.operation,
+            OperationType::Deposit | OperationType::Withdrawal
+        ) {
+            self.max_transaction_id = self.max_transaction_id.max(Some(transaction.id));
+        }
+
+        // Queries and opens don't change anything, so repeating them is harmless
+        let fingerprint = (self.config.dedupe_fingerprints
+            && !matches!(
+                transaction.operation,
+                OperationType::Query | OperationType::Open
+            ))
+        .then(|| {
+            (
+                transaction.operation.clone(),
+                client_id,
+                transaction.id,
+                transaction.amount,
+            )
+        });
+        if fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| self.fingerprints.contains(fingerprint))
+        {
+            return Err(ProcessingError::DuplicateFingerprint(transaction.id));
+        }
+
+        self.apply(transaction)?;
+
+        if let Some(fingerprint) = fingerprint {
+            self.fingerprints.insert(fingerprint);
+        }
+
+        if let (Some(callback), Some(transaction), Some(account)) = (
+            self.on_applied.as_mut(),
+            observed,
+            self.accounts.get(client_id),
+        ) {
+            callback(&transaction, account);
+        }
+
+        // Conversion already succeeded while applying
+        if let (Some(log), Some(mut transaction)) = (self.replay_log.as_mut(), replayed) {
+            if normalize_amount(&self.config, &mut transaction).is_ok() {
+                let _ = log.serialize(&transaction);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, mut transaction: Transaction<A>) -> Result<(), ProcessingError> {
+        // The account is created before any validation, so every client that appears
+        // in the input gets a report row even if all of its operations failed
+        let account = self.accounts.get_or_default(transaction.client_id);
+
+        if self.config.check_chronology {
+            check_chronology(&mut self.last_timestamp, &transaction)?;
+        }
+
+        // The account was just created if it didn't exist, nothing else to do
+        if transaction.operation == OperationType::Open {
+            return Ok(());
+        }
+
+        // Queries are answered even for locked accounts
+        if transaction.operation == OperationType::Query {
+            if self.config.verbose {
+                let _ = writeln!(
+                    self.error_sink,
+                    "Query for client `{}`: available `{}`, held `{}`, total `{}`",
+                    transaction.client_id,
+                    account.available,
+                    account.held,
+                    account.available.saturating_add(account.held)
+                );
+            }
+            return Ok(());
+        }
+
+        normalize_amount(&self.config, &mut transaction)?;
+
+        // Open disputes of a locked account can still be settled, so held funds don't get stranded
+        let settles_dispute = matches!(
+            transaction.operation,
+            OperationType::Resolve | OperationType::Chargeback
+        );
+        if account.locked && !settles_dispute {
+            return Err(ProcessingError::AccountLocked(transaction.client_id));
+        };
+
+        // Currencies can't be mixed within an account
+        if let (Some(currency), Some(established)) = (&transaction.currency, &account.currency) {
+            if currency != established {
+                return Err(ProcessingError::CurrencyMismatch(
+                    transaction.id,
+                    currency.clone(),
+                    established.clone(),
+                ));
+            }
+        }
+        let currency = transaction.currency.clone();
+
+        // Chargebacks are exempt, their amount is a partial chargeback
+        let takes_no_amount = matches!(
+            transaction.operation,
+            OperationType::Dispute | OperationType::Resolve
+        );
+        if self.config.reject_dispute_amounts && takes_no_amount && transaction.amount.is_some() {
+            return Err(ProcessingError::UnexpectedAmount(transaction.id));
+        }
+
+        // Trailing zeros don't count as precision
+        if let Some(amount) = transaction.amount {
+            let scale = amount.to_decimal().normalize().scale();
+            if self.config.strict && scale > self.config.decimal_places {
+                return Err(ProcessingError::ExcessivePrecision(
+                    transaction.id,
+                    self.config.decimal_places,
+                ));
+            }
+        }
+
+        if let Some(hook) = self.pre_hook.as_mut() {
+            if let HookDecision::Reject(reason) = hook(&transaction, account) {
+                return Err(ProcessingError::RejectedByHook(transaction.id, reason));
+            }
+        }
+
+        match transaction.operation {
+            OperationType::Deposit => {
+                let client_id = transaction.client_id;
+                operation_deposit(account, transaction, &self.config)?;
+
+                // Only a warning, the deposit stays applied
+                if let Some(threshold) = self.config.balance_warning_threshold {
+                    let available = account.available.to_decimal();
+                    if available > threshold {
+                        self.warnings.push(Warning::BalanceThresholdExceeded {
+                            client_id,
+                            available,
+                        });
+                    }
+                }
+            }
+            OperationType::Withdrawal => operation_withdraw(account, transaction, &self.config)?,
+            OperationType::Dispute => {
+                let (client_id, id) = (transaction.client_id, transaction.id);
+                let shortfall = operation_dispute(account, transaction, &self.config)?;
+                if shortfall > A::ZERO {
+                    self.warnings.push(Warning::PartialHold {
+                        client_id,
+                        tx: id,
+                        shortfall: shortfall.to_decimal(),
+                    });
+                }
+
+                // Held grows without any available funds backing it
+                let is_withdrawal = account
+                    .transactions
+                    .get(&id)
+                    .is_some_and(|disputed| disputed.operation == OperationType::Withdrawal);
+                if self.config.warn_unbacked_disputes && is_withdrawal {
+                    let total = account.available.saturating_add(account.held).to_decimal();
+                    let net_deposits = account.lifetime_totals().net_position();
+                    if total > net_deposits {
+                        self.warnings.push(Warning::UnbackedWithdrawalDispute {
+                            client_id,
+                            tx: id,
+                            total,
+                            net_deposits,
+                        });
+                    }
+                }
+            }
+            OperationType::Resolve => operation_resolve(account, transaction, &self.config)?,
+            OperationType::Chargeback => operation_chargeback(account, transaction, &self.config)?,
+            // Handled before the lock check
+            OperationType::Query | OperationType::Open => {}
+        }
+
+        if account.currency.is_none() {
+            account.currency = currency;
+        }
+
+        Ok(())
+    }
+}
+
+const ERROR_LOG_HEADER: [&str; 5] = ["line", "error_type", "tx", "client", "message"];
+
+// Separate error file, see `Engine::set_error_log`
+enum ErrorLog {
+    // Fixed columns, empty where the location isn't known
+    Csv(Box<Writer<Box<dyn Write>>>),
+    Ndjson(Box<dyn Write>),
+}
+
+impl ErrorLog {
+    fn write(&mut self, entry: &ErrorLogEntry) -> std::io::Result<()> {
+        match self {
+            ErrorLog::Csv(writer) => {
+                let optional = |value: Option<String>| value.unwrap_or_default();
+                writer.write_record([
+                    optional(entry.line.map(|line| line.to_string())),
+                    entry.error_type.to_string(),
+                    optional(entry.tx.map(|tx| tx.to_string())),
+                    optional(entry.client.map(|client| client.to_string())),
+                    entry.message.clone(),
+                ])?;
+                Ok(())
+            }
+            ErrorLog::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, entry)?;
+                writeln!(writer)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ErrorLog::Csv(writer) => writer.flush(),
+            ErrorLog::Ndjson(writer) => writer.flush(),
+        }
+    }
+}
+
+// 64 bit FNV-1a of everything written into it
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for byte in buf {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Checked before anything is merged, so a conflict leaves both engines untouched
+fn check_merge_conflicts<A: Amount>(
+    client_id: ClientId,
+    ours: &AccountData<A>,
+    theirs: &AccountData<A>,
+    config: &EngineConfig,
+) -> Result<(), EngineError> {
+    if let (Some(our_currency), Some(their_currency)) = (&ours.currency, &theirs.currency) {
+        if our_currency != their_currency {
+            return Err(EngineError::CurrencyConflict(client_id));
+        }
+    }
+
+    let their_ids = theirs
+        .transactions
+        .keys()
+        .chain(&theirs.withdrawal_ids)
+        .chain(&theirs.deposit_ids);
+    for &id in their_ids {
+        for our_operation in id_uses(ours, id) {
+            for their_operation in id_uses(theirs, id) {
+                let shared_id = config.dedupe_per_operation && our_operation != their_operation;
+                if !shared_id {
+                    return Err(EngineError::MergeConflict(client_id, id));
+                }
+            }
+        }
+    }
+
+    let overflow = || ProcessingError::Overflow(None);
+    let available = ours
+        .available
+        .checked_add(theirs.available)
+        .ok_or_else(overflow)?;
+    let held = ours.held.checked_add(theirs.held).ok_or_else(overflow)?;
+    available.checked_add(held).ok_or_else(overflow)?;
+
+    Ok(())
+}
+
+// Operations an id was used for, whether the transaction was stored or not
+fn id_uses<A>(account: &AccountData<A>, id: TransactionId) -> impl Iterator<Item = OperationType> {
+    let stored = account
+        .transactions
+        .get(&id)
+        .map(|transaction| transaction.operation.clone());
+    let withdrawal = account
+        .withdrawal_ids
+        .contains(&id)
+        .then_some(OperationType::Withdrawal);
+    let deposit = account
+        .deposit_ids
+        .contains(&id)
+        .then_some(OperationType::Deposit);
+    stored.into_iter().chain(withdrawal).chain(deposit)
+}
+
+// Dust is only summed if rows carry it
+fn add_to_totals(totals: &mut ReportTotals, row: &ReportRow) -> Result<(), EngineError> {
+    let add = |a: Decimal, b: Decimal| a.checked_add(b).ok_or(ProcessingError::Overflow(None));
+
+    totals.available = add(totals.available, row.available)?;
+    totals.held = add(totals.held, row.held)?;
+    totals.total = add(totals.total, row.total)?;
+    totals.dust = match (totals.dust, row.dust) {
+        (Some(sum), Some(dust)) => Some(add(sum, dust)?),
+        (sum, dust) => sum.or(dust),
+    };
+    Ok(())
+}
+
+// Sums can't overflow, `check_merge_conflicts` has checked them
+fn merge_account<A: Amount>(ours: &mut AccountData<A>, theirs: AccountData<A>) {
+    ours.available = ours.available.saturating_add(theirs.available);
+    ours.held = ours.held.saturating_add(theirs.held);
+    ours.opening_held = ours.opening_held.saturating_add(theirs.opening_held);
+    ours.locked |= theirs.locked;
+    if ours.currency.is_none() {
+        ours.currency = theirs.currency;
+    }
+
+    for (id, transaction) in theirs.transactions {
+        ours.transactions.entry(id).or_insert(transaction);
+    }
+    ours.under_dispute.extend(theirs.under_dispute);
+    ours.withdrawal_ids.extend(theirs.withdrawal_ids);
+    ours.deposit_ids.extend(theirs.deposit_ids);
+    ours.partial_holds.extend(theirs.partial_holds);
+    ours.withdrawal_disputes_held = ours
+        .withdrawal_disputes_held
+        .saturating_add(theirs.withdrawal_disputes_held);
+    for (id, count) in theirs.dispute_counts {
+        *ours.dispute_counts.entry(id).or_default() += count;
+    }
+    ours.events.extend(theirs.events);
+
+    ours.total_deposited = ours.total_deposited.saturating_add(theirs.total_deposited);
+    ours.total_withdrawn = ours.total_withdrawn.saturating_add(theirs.total_withdrawn);
+    ours.total_disputed_withdrawals = ours
+        .total_disputed_withdrawals
+        .saturating_add(theirs.total_disputed_withdrawals);
+    ours.total_charged_back = ours
+        .total_charged_back
+        .saturating_add(theirs.total_charged_back);
+}
+
+// Amounts are processed in whole units, in canonical form unless disabled
+fn normalize_amount<A: Amount>(
+    config: &EngineConfig,
+    transaction: &mut Transaction<A>,
+) -> Result<(), ProcessingError> {
+    if transaction.operation == OperationType::Query {
+        return Ok(());
+    }
+    if config.amounts_in_minor_units {
+        let id = transaction.id;
+        transaction.amount = transaction
+            .amount
+            .map(|amount| {
+                let units = from_minor_units(amount.to_decimal(), id)?;
+                A::from_decimal(units).ok_or(ProcessingError::Overflow(Some(id)))
+            })
+            .transpose()?;
+    }
+    if config.normalize_amounts {
+        transaction.amount = transaction.amount.map(|amount| amount.normalize());
+    }
+    Ok(())
+}
+
+// Provenance line of a report with `report_metadata`
+fn metadata_line() -> String {
+    let now = DateTime::<Utc>::from(std::time::SystemTime::now());
+    format!(
+        "# transponster v{} generated at {}",
+        env!("CARGO_PKG_VERSION"),
+        now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    )
+}
+
+// Input format settings shared by all sources
+pub fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.flexible(true).trim(Trim::All);
+    builder
+}
+
+// Most frequent of comma, tab and semicolon in the first line, comma if there's no
+// single most frequent one
+pub fn sniff_delimiter(input: &[u8]) -> u8 {
+    let first_line = input
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let count = |delimiter: u8| first_line.iter().filter(|byte| **byte == delimiter).count();
+
+    let mut counts = [b',', b'\t', b';'].map(|delimiter| (count(delimiter), delimiter));
+    counts.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+    match counts {
+        [(most, delimiter), (second, _), _] if most > second => delimiter,
+        _ => b',',
+    }
+}
+
+// Position is kept for error reporting
+fn rewrite_field(record: &StringRecord, index: usize, value: &str) -> StringRecord {
+    let mut normalized: StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match i == index {
+            true => value,
+            false => field,
+        })
+        .collect();
+    normalized.set_position(record.position().cloned());
+    normalized
+}
+
+// `1.234,56` becomes `1234.56`. A `.` has to separate groups of three digits, anything
+// else (e.g. `0.5`, which could be a decimal dot) is None.
+fn comma_amount_to_dot(amount: &str) -> Option<String> {
+    let compact: String = amount.chars().filter(|c| !c.is_whitespace()).collect();
+    let (integer, fraction) = compact.split_once(',').unwrap_or((&compact, ""));
+    let mut groups = integer.split('.');
+    let first = groups.next().unwrap_or_default();
+
+    let well_formed = !integer.contains('.')
+        || (1..=3).contains(&first.trim_start_matches(['-', '+']).len())
+            && groups.all(|group| group.len() == 3 && group.chars().all(|c| c.is_ascii_digit()));
+    match well_formed && !fraction.contains('.') {
+        true => Some(compact.replace('.', "").replace(',', ".")),
+        false => None,
+    }
+}
+
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+// `$1.5` becomes `1.5`, only a single leading symbol is removed
+fn strip_currency_symbol(amount: &str) -> &str {
+    let trimmed = amount.trim();
+    trimmed
+        .strip_prefix(CURRENCY_SYMBOLS)
+        .unwrap_or(trimmed)
+        .trim_start()
+}
+
+// `1,000.50` becomes `1000.50`. Only comma separated groups of three digits are
+// accepted, anything else (e.g. `1,00`, which could be a decimal comma) is kept as
+// is and fails to parse.
+fn strip_thousands_separators(amount: &str) -> String {
+    let (integer, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let mut groups = integer.split(',');
+    let first = groups.next().unwrap_or_default();
+
+    let well_formed = (1..=3).contains(&first.trim_start_matches(['-', '+']).len())
+        && groups.all(|group| group.len() == 3 && group.chars().all(|c| c.is_ascii_digit()))
+        && !fraction.contains(',');
+    match well_formed {
+        true => amount.replace(',', ""),
+        false => amount.to_string(),
+    }
+}
+
+// Rows without a timestamp are not checked and don't move the clock
+fn check_chronology<A>(
+    last_timestamp: &mut Option<DateTime<Utc>>,
+    transaction: &Transaction<A>,
+) -> Result<(), ProcessingError> {
+    let Some(timestamp) = transaction.timestamp else {
+        return Ok(());
+    };
+
+    if last_timestamp.is_some_and(|last| timestamp < last) {
+        return Err(ProcessingError::OutOfOrder(transaction.id));
+    }
+
+    *last_timestamp = Some(timestamp);
+
+    Ok(())
+}
+
+fn from_minor_units(amount: Decimal, id: TransactionId) -> Result<Decimal, ProcessingError> {
+    if !amount.fract().is_zero() {
+        return Err(ProcessingError::FractionalMinorUnits(id));
+    }
+
+    amount
+        .checked_div(Decimal::from(MINOR_UNITS_PER_UNIT))
+        .map(|amount| amount.normalize())
+        .ok_or(ProcessingError::Overflow(Some(id)))
+}
+
+fn operation_deposit<A: Amount>(
+    account: &mut AccountData<A>,
+    transaction: Transaction<A>,
+    config: &EngineConfig,
+) -> Result<(), ProcessingError> {
+    check_duplicate(account, &transaction, config)?;
+
+    let amount = transaction
+        .amount
+        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
+
+    if amount < A::ZERO {
+        return Err(ProcessingError::NegativeAmount);
+    }
+
+    if config.reject_zero_amount && amount == A::ZERO {
+        return Err(ProcessingError::ZeroAmount(transaction.id));
+    }
+
+    let new_available = account
+        .available
+        .checked_add(amount)
+        .ok_or(ProcessingError::Overflow(Some(transaction.id)))?;
+
+    if let Some(cap) = config.max_account_balance {
+        // A total that doesn't even fit is over any cap
+        let within_cap = new_available
+            .checked_add(account.held)
+            .is_some_and(|total| total.to_decimal() <= cap);
+        if !within_cap {
+            return Err(ProcessingError::BalanceCapExceeded(transaction.client_id));
+        }
+    }
+
+    account.available = new_available;
+    // Only statistics, they must not block a deposit
+    account.total_deposited = account.total_deposited.saturating_add(amount);
+    release_settled_id(account, transaction.id, config);
+
+    // The id can be taken by a withdrawal when deduplicating per operation
+    if account.transactions.contains_key(&transaction.id) {
+        account.deposit_ids.insert(transaction.id);
+    } else {
+        account.transactions.insert(transaction.id, transaction);
+    }
+
+    Ok(())
+}
+
+// Ids are unique per account, or per operation type with `dedupe_per_operation`
+fn check_duplicate<A: Amount>(
+    account: &AccountData<A>,
+    transaction: &Transaction<A>,
+    config: &EngineConfig,
+) -> Result<(), ProcessingError> {
+    let duplicate = match config.dedupe_per_operation {
+        true => account.has_operation(&transaction.operation, transaction.id),
+        false => account.has_transaction(transaction.id),
+    };
+    let reusable = config.reuse_settled_ids && account.is_settled(transaction.id);
+    if duplicate && !reusable {
+        return Err(ProcessingError::DuplicatedTransaction(
+            transaction.id,
+            transaction.client_id,
+        ));
+    }
+    Ok(())
+}
+
+// Forgets the settled transaction an applied row reuses the id of, so the new one is
+// stored at the end of the history and its disputes are counted from zero
+fn release_settled_id<A: Amount>(
+    account: &mut AccountData<A>,
+    id: TransactionId,
+    config: &EngineConfig,
+) {
+    if config.reuse_settled_ids && account.is_settled(id) {
+        account.transactions.shift_remove(&id);
+        account.dispute_counts.remove(&id);
+    }
+}
+
+fn operation_withdraw<A: Amount>(
+    account: &mut AccountData<A>,
+    transaction: Transaction<A>,
+    config: &EngineConfig,
+) -> Result<(), ProcessingError> {
+    check_duplicate(account, &transaction, config)?;
+
+    let amount = transaction
+        .amount
+        .ok_or(ProcessingError::MissingAmount(transaction.id))?;
+
+    if amount < A::ZERO {
+        return Err(ProcessingError::NegativeAmount);
+    }
+
+    if config.reject_zero_amount && amount == A::ZERO {
+        return Err(ProcessingError::ZeroAmount(transaction.id));
+    }
+
+    if account.available < amount {
+        return Err(ProcessingError::InsufficientFounds(
+            transaction.id,
+            transaction.client_id,
+        ));
+    }
+
+    account.available = account
+        .available
+        .checked_sub(amount)
+        .ok_or(ProcessingError::Underflow(transaction.id))?;
+    account.total_withdrawn = account.total_withdrawn.saturating_add(amount);
+    release_settled_id(account, transaction.id, config);
+
+    // Withdrawals are only needed in full if they can be disputed, and only if the id
+    // isn't taken by a deposit when deduplicating per operation
+    if config.store_withdrawals_for_dispute && !account.transactions.contains_key(&transaction.id) {
+        account.transactions.insert(transaction.id, transaction);
+    } else {
+        account.withdrawal_ids.insert(transaction.id);
+    }
+
+    Ok(())
+}
+
+// Transaction referenced by a dispute, resolve or chargeback row
+struct DisputedTransaction<A> {
+    id: TransactionId,
+    operation: DisputedOperation,
+    amount: A,
+}
+
+// Only deposits and withdrawals can be disputed
+#[derive(Clone, Copy)]
+enum DisputedOperation {
+    Deposit,
+    Withdrawal,
+}
+
+// Accounts are per client, so the transaction id is unique within it. Input only stores
+// a client's own transactions in its account, but a store passed to `with_store` can
+// hold anything, so the owner is still matched: a row can't reference somebody else's
+// transaction.
+fn find_disputed<A: Amount>(
+    account: &AccountData<A>,
+    transaction: &Transaction<A>,
+) -> Result<DisputedTransaction<A>, ProcessingError> {
+    let referenced_transaction = account
+        .transactions
+        .get(&transaction.id)
+        .filter(|referenced| referenced.client_id == transaction.client_id)
+        .ok_or(ProcessingError::MissingTransaction(transaction.id))?;
+
+    let operation = match referenced_transaction.operation {
+        OperationType::Deposit => DisputedOperation::Deposit,
+        OperationType::Withdrawal => DisputedOperation::Withdrawal,
+        _ => {
+            return Err(ProcessingError::InvalidOperationUnderDispute(
+                referenced_transaction.operation.clone(),
+                transaction.id,
+            ))
+        }
+    };
+
+    // Clamped disputes only hold a part of the amount
+    let amount = match account.partial_holds.get(&referenced_transaction.id) {
+        Some(hold) => *hold,
+        None => referenced_transaction
+            .amount
+            .ok_or(ProcessingError::CorruptDisputedAmount(transaction.id))?,
+    };
+
+    Ok(DisputedTransaction {
+        id: referenced_transaction.id,
+        operation,
+        amount,
+    })
+}
+
+// Held funds of withdrawal disputes aren't taken from available, so they are capped by
+// the lifetime withdrawn total. Input alone can't exceed it (a withdrawal has at most
+// one open dispute), but a merged or externally changed state can. Returns the new
+// `withdrawal_disputes_held`.
+fn check_withdrawal_disputes_backed<A: Amount>(
+    account: &AccountData<A>,
+    disputed: &DisputedTransaction<A>,
+    dispute_id: TransactionId,
+) -> Result<A, ProcessingError> {
+    account
+        .withdrawal_disputes_held
+        .checked_add(disputed.amount)
+        .filter(|held| *held <= account.total_withdrawn)
+        .ok_or(ProcessingError::WithdrawalDisputeExceedsWithdrawn(
+            dispute_id,
+        ))
+}
+
+// What is left of `withdrawal_disputes_held` once the dispute is closed
+fn release_withdrawal_dispute<A: Amount>(
+    account: &AccountData<A>,
+    disputed: &DisputedTransaction<A>,
+    id: TransactionId,
+) -> Result<A, ProcessingError> {
+    match disputed.operation {
+        DisputedOperation::Deposit => Ok(account.withdrawal_disputes_held),
+        DisputedOperation::Withdrawal => {
+            release_held(account.withdrawal_disputes_held, disputed.amount, id)
+        }
+    }
+}
+
+// Returns the part of the disputed amount that couldn't be held
+fn operation_dispute<A: Amount>(
+    account: &mut AccountData<A>,
+    transaction: Transaction<A>,
+    config: &EngineConfig,
+) -> Result<A, ProcessingError> {
+    let disputed = find_disputed(account, &transaction)?;
+
+    // Check duplicated dispute for a transaction
+    if account.under_dispute.contains(&disputed.id) {
+        return Err(ProcessingError::DuplicatedDispute(
+            transaction.id,
+            disputed.id,
+            transaction.client_id,
+        ));
+    }
+
+    // Resolved transactions can be disputed again, up to the configured limit
+    let disputes = account
+        .dispute_counts
+        .get(&disputed.id)
+        .copied()
+        .unwrap_or(0);
+    if let Some(limit) = config.max_disputes_per_transaction {
+        if disputes >= limit {
+            return Err(ProcessingError::DisputeLimitExceeded(disputed.id, limit));
+        }
+    }
+
+    let mut shortfall = A::ZERO;
+    match disputed.operation {
+        DisputedOperation::Deposit => {
+            // Already withdrawn part of the deposit can't be held in clamping mode
+            let mut hold = disputed.amount;
+            if config.clamp_available_on_dispute && account.available < hold {
+                hold = account.available.max(A::ZERO);
+                shortfall = disputed
+                    .amount
+                    .checked_sub(hold)
+                    .ok_or(ProcessingError::Underflow(transaction.id))?;
+            }
+
+            // We need to do both checked operations to keep the transaction valid
+            let new_available = account
+                .available
+                .checked_sub(hold)
+                .ok_or(ProcessingError::Underflow(transaction.id))?;
+
+            let new_held = account
+                .held
+                .checked_add(hold)
+                .ok_or(ProcessingError::Overflow(Some(transaction.id)))?;
+
+            account.available = new_available;
+            account.held = new_held;
+            if shortfall > A::ZERO {
+                account.partial_holds.insert(disputed.id, hold);
+            }
+        }
+        DisputedOperation::Withdrawal => {
+            // The other way around. I guess it means withdrawn money was
+            // not received, so we put it back for now
+            let withdrawal_disputes_held =
+                check_withdrawal_disputes_backed(account, &disputed, transaction.id)?;
+            account.held = account
+                .held
+                .checked_add(disputed.amount)
+                .ok_or(ProcessingError::Overflow(Some(transaction.id)))?;
+            account.withdrawal_disputes_held = withdrawal_disputes_held;
+            account.total_disputed_withdrawals = account
+                .total_disputed_withdrawals
+                .saturating_add(disputed.amount);
+        }
+    }
+
+    account.under_dispute.insert(disputed.id);
+    account.dispute_counts.insert(disputed.id, disputes + 1);
+    if config.record_events {
+        account
+            .events
+            .push(AccountEvent::DisputeOpened(disputed.id));
+    }
+
+    Ok(shortfall)
+}
+
+fn operation_resolve<A: Amount>(
+    account: &mut AccountData<A>,
+    transaction: Transaction<A>,
+    config: &EngineConfig,
+) -> Result<(), ProcessingError> {
+    let disputed = find_disputed(account, &transaction)?;
+
+    // Check if transaction under dispute, repeated resolves can be tolerated
+    if !account.under_dispute.contains(&disputed.id) {
+        if config.lenient_resolves {
+            return Ok(());
+        }
+        return Err(ProcessingError::IncorrectResolve(
+            transaction.operation,
+            transaction.id,
+        ));
+    }
+
+    // Both kinds release the held amount back to available
+    match disputed.operation {
+        DisputedOperation::Deposit | DisputedOperation::Withdrawal => {
+            let new_available = account
+                .available
+                .checked_add(disputed.amount)
+                .ok_or(ProcessingError::Overflow(Some(transaction.id)))?;
+
+            let new_held = release_held(account.held, disputed.amount, transaction.id)?;
+            let withdrawal_disputes_held =
+                release_withdrawal_dispute(account, &disputed, transaction.id)?;
+
+            account.available = new_available;
+            account.held = new_held;
+            account.withdrawal_disputes_held = withdrawal_disputes_held;
+        }
+    }
+
+    account.under_dispute.remove(&disputed.id);
+    account.partial_holds.remove(&disputed.id);
+    if config.record_events {
+        account.events.push(AccountEvent::Resolved(disputed.id));
+    }
+
+    Ok(())
+}
+
+// Held funds only come from disputes, so releasing more than is held means the
+// dispute bookkeeping is broken, not that the input is bad
+fn release_held<A: Amount>(held: A, amount: A, id: TransactionId) -> Result<A, ProcessingError> {
+    held.checked_sub(amount)
+        .filter(|new_held| *new_held >= A::ZERO)
+        .ok_or(ProcessingError::HeldUnderflow(id))
+}
+
+fn operation_chargeback<A: Amount>(
+    account: &mut AccountData<A>,
+    transaction: Transaction<A>,
+    config: &EngineConfig,
+) -> Result<(), ProcessingError> {
+    let disputed = find_disputed(account, &transaction)?;
+
+    // Check if transaction under dispute
+    if !account.under_dispute.contains(&disputed.id) {
+        return Err(ProcessingError::IncorrectChargeback(
+            transaction.operation,
+            transaction.id,
+        ));
+    }
+
+    // Partial chargebacks give the rest of the disputed amount back
+    let charged = transaction.amount.unwrap_or(disputed.amount);
+    if charged < A::ZERO {
+        return Err(ProcessingError::NegativeAmount);
+    }
+    if charged > disputed.amount {
+        return Err(ProcessingError::ExcessiveChargeback(
+            transaction.id,
+            disputed.amount.to_decimal(),
+        ));
+    }
+
+    // Both kinds drop the whole disputed amount from held
+    match disputed.operation {
+        DisputedOperation::Deposit | DisputedOperation::Withdrawal => {
+            let returned = disputed
+                .amount
+                .checked_sub(charged)
+                .ok_or(ProcessingError::Underflow(transaction.id))?;
+            let new_available = account
+                .available
+                .checked_add(returned)
+                .ok_or(ProcessingError::Overflow(Some(transaction.id)))?;
+
+            let new_held = release_held(account.held, disputed.amount, transaction.id)?;
+            let withdrawal_disputes_held =
+                release_withdrawal_dispute(account, &disputed, transaction.id)?;
+
+            account.available = new_available;
+            account.held = new_held;
+            account.withdrawal_disputes_held = withdrawal_disputes_held;
+            account.total_charged_back = account.total_charged_back.saturating_add(charged);
+        }
+    }
+
+    account.under_dispute.remove(&disputed.id);
+    account.partial_holds.remove(&disputed.id);
+    if config.record_events {
+        account.events.push(AccountEvent::ChargedBack(disputed.id));
+    }
+
+    account.locked = true;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use crate::engine::config::{
+        DecimalLocale, DustHandling, EngineConfig, ErrorLogFormat, LogFormat, QuoteStyle,
+        SortColumn,
+    };
+    use crate::engine::error::{EngineError, InvariantViolation, ProcessingError, Warning};
+    use crate::engine::generator::generate_transactions;
+    use crate::engine::models::AccountData;
+    use crate::engine::models::OperationType;
+    use crate::engine::models::{
+        AccountEvent, AccountsMap, ClientId, ProcessingStats, ReferenceCounts, ReportRow,
+        TransactionId,
+    };
+    use crate::engine::report::{diff_reports, read_binary_report, read_report, ReportSink};
+    use crate::engine::store::AccountStore;
+
+    use super::amount::MinorUnits;
+    use super::{HookDecision, Transaction};
+
+    // Clonable in-memory writer, so the test can read what the engine logged
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).into_owned()
+        }
+    }
+
+    fn process_csv(engine: &mut super::Engine, input: &str) {
+        let reader = super::reader_builder().from_reader(input.as_bytes());
+        engine.process_from_reader(reader).unwrap();
+    }
+
+    // Transaction without the optional columns, like most rows
+    fn transaction(
+        id: TransactionId,
+        operation: OperationType,
+        client_id: ClientId,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction {
+            id,
+            operation,
+            client_id,
+            amount,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    // Yields at most `chunk` bytes per read, like a fragmented network stream
+    struct Fragmented<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl std::io::Read for Fragmented<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn error_duplicated_transaction() {
+        let mut engine = super::Engine::new();
+        engine
+            .process_one(transaction(1, OperationType::Deposit, 10, Some(dec!(1))))
+            .unwrap();
+
+        let result =
+            engine.process_one(transaction(1, OperationType::Withdrawal, 10, Some(dec!(2))));
+
+        assert_eq!(result, Err(ProcessingError::DuplicatedTransaction(1, 10)));
+    }
+
+    #[test]
+    fn error_insufficient_founds() {
+        let mut engine = super::Engine::new();
+        engine
+            .process_one(transaction(1, OperationType::Deposit, 10, Some(dec!(1))))
+            .unwrap();
+
+        let result =
+            engine.process_one(transaction(2, OperationType::Withdrawal, 10, Some(dec!(2))));
+
+        assert_eq!(result, Err(ProcessingError::InsufficientFounds(2, 10)));
+    }
+
+    #[test]
+    fn withdrawals_not_stored_without_withdrawal_disputes() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            store_withdrawals_for_dispute: false,
+            ..Default::default()
+        });
+        engine
+            .process_one(transaction(1, OperationType::Deposit, 10, Some(dec!(2))))
+            .unwrap();
+        engine
+            .process_one(transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))))
+            .unwrap();
+
+        let account = engine.accounts.get(&10).unwrap();
+        assert!(account.transactions.contains_key(&1));
+        assert!(!account.transactions.contains_key(&2));
+
+        let duplicate =
+            engine.process_one(transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))));
+        assert_eq!(
+            duplicate,
+            Err(ProcessingError::DuplicatedTransaction(2, 10))
+        );
+
+        let dispute = engine.process_one(transaction(2, OperationType::Dispute, 10, None));
+        assert_eq!(dispute, Err(ProcessingError::MissingTransaction(2)));
+    }
+
+    #[test]
+    fn on_applied_fires_only_for_applied_transactions() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(5))),
+            transaction(3, OperationType::Withdrawal, 10, Some(dec!(1))),
+        ];
+
+        let applied = Rc::new(RefCell::new(Vec::new()));
+        let sink = applied.clone();
+
+        let mut engine = super::Engine::new();
+        engine.on_applied(Box::new(move |transaction, account| {
+            sink.borrow_mut().push((transaction.id, account.available));
+        }));
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(*applied.borrow(), vec![(1, dec!(2)), (3, dec!(1))]);
+    }
+
+    #[test]
+    fn account_created_for_failed_operations() {
+        let mut engine = super::Engine::new();
+
+        let result = engine.process_one(transaction(1, OperationType::Dispute, 10, None));
+
+        assert_eq!(result, Err(ProcessingError::MissingTransaction(1)));
+        assert_eq!(&AccountData::default(), engine.accounts.get(&10).unwrap());
+    }
+
+    #[test]
+    fn transactions_for_in_application_order() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(3, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(3, OperationType::Dispute, 10, None),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(0.5))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        let history: Vec<_> = engine
+            .transactions_for(10)
+            .unwrap()
+            .iter()
+            .map(|t| (t.id, t.operation.clone()))
+            .collect();
+
+        assert_eq!(
+            history,
+            vec![
+                (3, OperationType::Deposit),
+                (1, OperationType::Deposit),
+                (2, OperationType::Withdrawal),
+            ]
+        );
+        assert!(engine.transactions_for(11).is_none());
+    }
+
+    #[test]
+    fn json_error_log() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            log_format: LogFormat::Json,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount\ndeposit, 7, 1, 1.0\nwithdrawal, 7, 2, 5.0",
+        );
+
+        let lines: Vec<serde_json::Value> = log
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![serde_json::json!({
+                "error_type": "InsufficientFounds",
+                "line": 3,
+                "tx": 2,
+                "client": 7,
+                "message": "insufficient founds for transaction `2`; account: `7`",
+            })]
+        );
+    }
+
+    #[test]
+    fn plain_error_log() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount\nwithdrawal, 7, 2, 5.0",
+        );
+
+        assert_eq!(
+            log.contents(),
+            "Processing error: insufficient founds for transaction `2`; account: `7`\n"
+        );
+    }
+
+    #[test]
+    fn default_engine() {
+        let mut engine = super::Engine::default();
+        engine
+            .process_one(transaction(1, OperationType::Deposit, 10, Some(dec!(1))))
+            .unwrap();
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+        assert_eq!(engine.stats(), Default::default());
+    }
+
+    #[test]
+    fn error_out_of_order_timestamp() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            check_chronology: true,
+            ..Default::default()
+        });
+        engine
+            .process_one(Transaction {
+                timestamp: Some("2024-05-02T10:00:00Z".parse().unwrap()),
+                ..transaction(1, OperationType::Deposit, 10, Some(dec!(1)))
+            })
+            .unwrap();
+
+        let result = engine.process_one(Transaction {
+            timestamp: Some("2024-05-01T10:00:00Z".parse().unwrap()),
+            ..transaction(2, OperationType::Deposit, 10, Some(dec!(1)))
+        });
+
+        assert_eq!(result, Err(ProcessingError::OutOfOrder(2)));
+        assert_eq!(engine.accounts.get(&10).unwrap().available, dec!(1));
+    }
+
+    #[test]
+    fn invariants_hold_on_normal_data() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(3))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(2, OperationType::Dispute, 10, None),
+            transaction(3, OperationType::Deposit, 11, Some(dec!(1))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn invariants_detect_held_mismatch() {
+        let mut engine = super::Engine::new();
+        engine
+            .process_one(transaction(1, OperationType::Deposit, 10, Some(dec!(3))))
+            .unwrap();
+        engine.accounts.get_mut(&10).unwrap().held = dec!(1);
+
+        assert_eq!(
+            engine.verify_invariants(),
+            Err(vec![InvariantViolation::HeldMismatch {
+                client_id: 10,
+                held: dec!(1),
+                expected: dec!(0),
+            }])
+        );
+    }
+
+    #[test]
+    fn generated_transactions_apply_cleanly() {
+        let mut engine = super::Engine::new();
+        generate_transactions(10_000, 42)
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn generator_is_deterministic() {
+        let ids = |seed| {
+            generate_transactions(100, seed)
+                .into_iter()
+                .map(|t| (t.client_id, t.id, t.amount))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(7), ids(7));
+        assert_ne!(ids(7), ids(8));
+    }
+
+    #[test]
+    fn skip_bad_rows() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            log_format: LogFormat::Json,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount\ndeposit, 7, 1, 1.0\nrefund, 7, 2, 1.0\ndeposit, 7, 3, 2.0",
+        );
+
+        let entry: serde_json::Value = serde_json::from_str(log.contents().trim()).unwrap();
+        assert_eq!(entry["error_type"], "Parsing");
+        assert_eq!(entry["line"], 3);
+        assert_eq!(engine.accounts.get(&7).unwrap().available, dec!(3));
+        assert_eq!(engine.stats().read, 3);
+        assert_eq!(engine.stats().skipped, 1);
+    }
+
+    #[test]
+    fn bad_row_aborts_by_default() {
+        let reader = super::reader_builder()
+            .from_reader("type, client, tx, amount\nrefund, 7, 2, 1.0".as_bytes());
+
+        let result = super::Engine::new().process_from_reader(reader);
+
+        assert!(matches!(result, Err(EngineError::Parsing(_))));
+    }
+
+    const MULTI_CLIENT_INPUT: &str = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 3, 3, 3.0
+        withdrawal, 2, 4, 1.0";
+
+    #[test]
+    fn only_clients_filter() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            only_clients: Some(HashSet::from([1, 2])),
+            ..Default::default()
+        });
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        assert_eq!(engine.accounts.keys().collect::<Vec<_>>(), vec![&1, &2]);
+        assert_eq!(engine.accounts.get(&2).unwrap().available, dec!(1));
+        assert_eq!(engine.stats().filtered, 1);
+    }
+
+    #[test]
+    fn exclude_clients_filter() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            exclude_clients: Some(HashSet::from([2])),
+            ..Default::default()
+        });
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        assert_eq!(engine.accounts.keys().collect::<Vec<_>>(), vec![&1, &3]);
+        assert_eq!(engine.stats().filtered, 2);
+    }
+
+    #[test]
+    fn balance_warning_threshold() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(60))),
+            transaction(2, OperationType::Deposit, 10, Some(dec!(50))),
+        ];
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            balance_warning_threshold: Some(dec!(100)),
+            ..Default::default()
+        });
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            engine.warnings(),
+            &[Warning::BalanceThresholdExceeded {
+                client_id: 10,
+                available: dec!(110),
+            }]
+        );
+        assert_eq!(engine.accounts.get(&10).unwrap().available, dec!(110));
+    }
+
+    #[test]
+    fn fragmented_stream() {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        generate_transactions(500, 3)
+            .into_iter()
+            .try_for_each(|t| writer.serialize(t))
+            .unwrap();
+        let input = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        let applied = Rc::new(RefCell::new(Vec::new()));
+        let sink = applied.clone();
+
+        let mut streamed = super::Engine::new();
+        streamed.on_applied(Box::new(move |transaction, _| {
+            sink.borrow_mut().push(transaction.id);
+        }));
+        let stream = Fragmented {
+            data: input.as_bytes(),
+            chunk: 3,
+        };
+        streamed
+            .process_stream(std::io::BufReader::with_capacity(2, stream))
+            .unwrap();
+
+        let mut whole = super::Engine::new();
+        process_csv(&mut whole, &input);
+
+        assert_eq!(applied.borrow().len(), 500);
+        assert_eq!(streamed.stats(), whole.stats());
+        assert_eq!(streamed.accounts, whole.accounts);
+    }
+
+    #[test]
+    fn progress_reports_final_count() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            progress_interval: Some(2),
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+        process_csv(&mut engine, "type, client, tx, amount\ndeposit, 4, 5, 1.0");
+
+        assert_eq!(
+            log.contents(),
+            "Processed 2 records\nProcessed 4 records\nProcessed 5 records\n"
+        );
+    }
+
+    #[test]
+    fn progress_follows_log_format() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            progress_interval: Some(3),
+            log_format: LogFormat::Json,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        assert_eq!(log.contents(), "{\"processed\":3}\n{\"processed\":4}\n");
+    }
+
+    fn engine_with_broken_held(config: EngineConfig) -> super::Engine {
+        let mut engine = super::Engine::with_config(config);
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount\ndeposit, 10, 1, 2.0\ndispute, 10, 1",
+        );
+        engine.accounts.get_mut(&10).unwrap().held = dec!(1);
+        engine
+    }
+
+    #[test]
+    fn error_held_underflow() {
+        let mut engine = engine_with_broken_held(EngineConfig::default());
+
+        let result = engine.process_one(transaction(1, OperationType::Resolve, 10, None));
+
+        assert_eq!(result, Err(ProcessingError::HeldUnderflow(1)));
+        assert_eq!(engine.accounts.get(&10).unwrap().held, dec!(1));
+    }
+
+    #[test]
+    fn held_underflow_fatal_in_strict_mode() {
+        let mut engine = engine_with_broken_held(EngineConfig {
+            strict: true,
+            ..Default::default()
+        });
+
+        let reader = super::reader_builder()
+            .from_reader("type, client, tx, amount\nchargeback, 10, 1".as_bytes());
+        let result = engine.process_from_reader(reader);
+
+        assert!(matches!(
+            result,
+            Err(EngineError::Processing(ProcessingError::HeldUnderflow(1)))
+        ));
+    }
+
+    #[test]
+    fn account_serialization_is_deterministic() {
+        let input = "type, client, tx, amount
+            deposit, 10, 1, 1.0
+            deposit, 10, 2, 1.0
+            deposit, 10, 3, 1.0
+            deposit, 10, 4, 1.0
+            dispute, 10, 4
+            dispute, 10, 2
+            dispute, 10, 3
+            dispute, 10, 1";
+
+        let serialized = || {
+            let mut engine = super::Engine::new();
+            process_csv(&mut engine, input);
+            serde_json::to_vec(engine.accounts.get(&10).unwrap()).unwrap()
+        };
+
+        let first = serialized();
+        assert_eq!(first, serialized());
+        assert!(String::from_utf8(first)
+            .unwrap()
+            .contains(r#""under_dispute":[1,2,3,4]"#));
+    }
+
+    #[test]
+    fn query_does_not_change_balance() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            verbose: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 10, 1, 1.5
+            query, 10
+            deposit, 10, 2, 1.0",
+        );
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(2.5),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+        assert_eq!(engine.stats().applied, 3);
+        assert_eq!(
+            log.contents(),
+            "Query for client `10`: available `1.5`, held `0`, total `1.5`\n"
+        );
+    }
+
+    #[test]
+    fn missing_tx_is_a_parsing_error() {
+        let input = "type, client, tx, amount
+            deposit, 10, , 5.0
+            deposit, 10, 1, 1.5
+            query, 10";
+
+        let mut engine = super::Engine::new();
+        let reader = super::reader_builder().from_reader(input.as_bytes());
+        assert!(matches!(
+            engine.process_from_reader(reader),
+            Err(EngineError::Parsing(_))
+        ));
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+        assert_eq!(engine.accounts[&10].available, dec!(1.5));
+        assert!(!engine.accounts[&10].transactions.contains_key(&0));
+        assert_eq!(engine.stats().skipped, 1);
+        assert_eq!(engine.stats().applied, 2);
+    }
+
+    #[test]
+    fn extreme_amounts_do_not_panic() {
+        let max = rust_decimal::Decimal::MAX;
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            amounts_in_minor_units: true,
+            ..Default::default()
+        });
+        process_csv(
+            &mut engine,
+            &format!(
+                "type, client, tx, amount
+                deposit, 1, 1, {max}
+                deposit, 1, 2, {max}
+                dispute, 1, 1
+                deposit, 1, 3, {max}
+                withdrawal, 1, 4, {max}
+                dispute, 1, 4
+                dispute, 1, 3
+                resolve, 1, 1
+                chargeback, 1, 4
+                deposit, 2, 5, 99999999999999999999999999999999
+                deposit, 2, 6, 0.0000000000000000000000000001"
+            ),
+        );
+
+        assert_eq!(engine.stats().read, 11);
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn error_currency_mismatch() {
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount, currency
+            deposit, 10, 1, 5.0,
+            deposit, 10, 2, 2.0, USD
+            withdrawal, 10, 3, 1.0, EUR
+            withdrawal, 10, 4, 1.0, USD
+            withdrawal, 10, 5, 1.0",
+        );
+
+        let result = engine.process_one(Transaction {
+            currency: Some("EUR".to_string()),
+            ..transaction(6, OperationType::Withdrawal, 10, Some(dec!(1)))
+        });
+
+        assert_eq!(
+            result,
+            Err(ProcessingError::CurrencyMismatch(
+                6,
+                "EUR".to_string(),
+                "USD".to_string()
+            ))
+        );
+        assert_eq!(
+            &AccountData {
+                available: dec!(5),
+                currency: Some("USD".to_string()),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+        assert_eq!(engine.stats().skipped, 1);
+    }
+
+    #[test]
+    fn two_deposits() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(2, OperationType::Deposit, 10, Some(dec!(1))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(2),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn two_withdrawals() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdraw_balance_positive() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(0.5))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0.5),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdraw_balance_negative() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1.5))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_and_dispute() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(1),
+                locked: false,
+                under_dispute: HashSet::from_iter(vec![1]),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_and_dispute_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(3))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(2))),
+            transaction(1, OperationType::Dispute, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(-2),
+                held: dec!(3),
+                locked: false,
+                under_dispute: HashSet::from_iter(vec![1]),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_dispute_and_resolve_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Resolve, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_dispute_and_chargeback_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_and_dispute_withdrawal() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(2, OperationType::Dispute, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                held: dec!(1),
+                locked: false,
+                under_dispute: HashSet::from_iter(vec![2]),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_dispute_and_chargeback_withdrawal() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(2, OperationType::Dispute, 10, None),
+            transaction(2, OperationType::Chargeback, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_dispute_and_chargeback_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(-1),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_dispute_and_resolve_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Resolve, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(1),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_withdrawal_dispute_and_resolve_withdrawal() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(1))),
+            transaction(2, OperationType::Dispute, 10, None),
+            transaction(2, OperationType::Resolve, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(2),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_dispute_resolve_dispute_and_chargeback_deposit() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Resolve, 10, None),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_disputed_together() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(5))),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(2))),
+            transaction(2, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(2, OperationType::Resolve, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(5),
+                locked: false,
+                under_dispute: HashSet::from_iter(vec![1]),
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+
+        engine
+            .process_one(transaction(1, OperationType::Chargeback, 10, None))
+            .unwrap();
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn dispute_of_other_clients_transaction() {
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 10, 1, 5.0",
+        );
+
+        let result = engine.process_one(transaction(1, OperationType::Dispute, 11, None));
+
+        assert_eq!(result, Err(ProcessingError::MissingTransaction(1)));
+        assert_eq!(engine.accounts.get(&10).unwrap().available, dec!(5));
+    }
+
+    #[test]
+    fn dispute_of_foreign_transaction_in_store() {
+        // Account of client 10 wrongly holds a deposit of client 11
+        let mut account = AccountData {
+            available: dec!(5),
+            ..Default::default()
+        };
+        account
+            .transactions
+            .insert(1, transaction(1, OperationType::Deposit, 11, Some(dec!(5))));
+        let mut accounts = AccountsMap::default();
+        accounts.insert(10, account);
+        let mut engine = super::Engine::with_store(EngineConfig::default(), accounts);
+
+        let result = engine.process_one(transaction(1, OperationType::Dispute, 10, None));
+
+        assert_eq!(result, Err(ProcessingError::MissingTransaction(1)));
+        let account = engine.accounts.get(&10).unwrap();
+        assert_eq!(account.available, dec!(5));
+        assert_eq!(account.held, dec!(0));
+    }
+
+    #[test]
+    fn merged_report_balances_carry_forward() {
+        let report = "\
+client,available,held,total,locked
+1,1.5,0.5,2.0,false
+2,3.0,0.0,3.0,true
+,4.5,0.5,5.0,";
+
+        let mut engine = super::Engine::new();
+        engine
+            .load_report_from_reader(super::reader_builder().from_reader(report.as_bytes()))
+            .unwrap();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, 1.0
+            dispute, 1, 7, ",
+        );
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(2.5),
+                held: dec!(0.5),
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+        assert_eq!(
+            &AccountData {
+                available: dec!(3.0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&2).unwrap()
+        );
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    // Subscribers must be `Send`, unlike `SharedBuffer`
+    #[derive(Clone, Default)]
+    struct EventBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for EventBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_withdrawal_emits_error_event() {
+        let events = EventBuffer::default();
+        let writer = events.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut engine = super::Engine::new();
+            engine.set_error_sink(Box::new(std::io::sink()));
+            process_csv(
+                &mut engine,
+                "type, client, tx, amount
+                deposit, 1, 1, 1.0
+                withdrawal, 1, 2, 2.0",
+            );
+        });
+
+        let events = String::from_utf8(events.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(events.lines().count(), 1);
+        assert!(events.contains("ERROR"));
+        assert!(events.contains("error_type=\"InsufficientFounds\""));
+        assert!(events.contains("tx=2"));
+    }
+
+    #[test]
+    fn zero_amount_deposit() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 0.0";
+
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().applied, 1);
+        assert!(engine.accounts[&1].has_transaction(1));
+
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            reject_zero_amount: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().skipped, 1);
+        assert!(!engine.accounts[&1].has_transaction(1));
+        assert_eq!(
+            errors.contents(),
+            "Processing error: Zero amount in transaction `1`\n"
+        );
+    }
+
+    #[test]
+    fn full_and_partial_chargeback() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            deposit, 2, 2, 10.0
+            dispute, 1, 1,
+            dispute, 2, 2,
+            chargeback, 1, 1,
+            chargeback, 2, 2, 4.0";
+
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, input);
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+        assert_eq!(
+            &AccountData {
+                available: dec!(6),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&2).unwrap()
+        );
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn chargeback_above_disputed_amount() {
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            dispute, 1, 1,",
+        );
+
+        let result = engine.process_one(transaction(
+            1,
+            OperationType::Chargeback,
+            1,
+            Some(dec!(10.5)),
+        ));
+
+        assert_eq!(
+            result,
+            Err(ProcessingError::ExcessiveChargeback(1, dec!(10)))
+        );
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(10),
+                locked: false,
+                under_dispute: HashSet::from_iter(vec![1]),
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+    }
+
+    #[test]
+    fn report_sink_gets_one_row_per_client() {
+        #[derive(Default)]
+        struct CollectingSink {
+            clients: Vec<ClientId>,
+            finished: usize,
+        }
+
+        impl ReportSink for CollectingSink {
+            fn emit(&mut self, row: &ReportRow) -> Result<(), EngineError> {
+                self.clients.push(row.client_id);
+                Ok(())
+            }
+
+            fn finish(&mut self) -> Result<(), EngineError> {
+                self.finished += 1;
+                Ok(())
+            }
+        }
+
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        let mut sink = CollectingSink::default();
+        engine.emit_report(&mut sink).unwrap();
+
+        assert_eq!(sink.clients, vec![1, 2, 3]);
+        assert_eq!(sink.finished, 1);
+    }
+
+    #[test]
+    fn comma_decimal_locale() {
+        // A comma inside the amount needs quoting in a comma separated file
+        let input = "type,client,tx,amount
+deposit,1,1,\"1.234,56\"
+deposit,2,2,7
+withdrawal,1,3,\"0,5\"";
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            decimal_locale: DecimalLocale::Comma,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.accounts[&1].available, dec!(1234.06));
+        assert_eq!(engine.accounts[&2].available, dec!(7));
+        assert_eq!(engine.stats().applied, 3);
+
+        // Dot locale doesn't accept it
+        let mut engine = super::Engine::new();
+        let reader = super::reader_builder().from_reader(input.as_bytes());
+        assert!(engine.process_from_reader(reader).is_err());
+    }
+
+    #[test]
+    fn comma_decimal_locale_rejects_decimal_dot() {
+        let input = "type,client,tx,amount
+deposit,1,1,0.5
+deposit,1,2,1.234
+deposit,1,3,\"1.23,4\"
+deposit,1,4,\"1,5.0\"";
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            decimal_locale: DecimalLocale::Comma,
+            skip_bad_rows: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.accounts[&1].available, dec!(1234));
+        assert_eq!(engine.stats().skipped, 3);
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            decimal_locale: DecimalLocale::Comma,
+            ..Default::default()
+        });
+        let reader = super::reader_builder().from_reader(input.as_bytes());
+        let Err(EngineError::Parsing(error)) = engine.process_from_reader(reader) else {
+            panic!("expected a parsing error");
+        };
+        assert!(
+            error.to_string().contains("invalid amount `0.5`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn sniff_delimiter() {
+        assert_eq!(super::sniff_delimiter(b"type,client,tx,amount\n"), b',');
+        assert_eq!(super::sniff_delimiter(b"type\tclient\ttx\tamount"), b'\t');
+        assert_eq!(
+            super::sniff_delimiter(b"type;client;tx;amount\na,b,c,d,e"),
+            b';'
+        );
+        // Ambiguous
+        assert_eq!(super::sniff_delimiter(b"type;client,tx"), b',');
+        assert_eq!(super::sniff_delimiter(b""), b',');
+    }
+
+    #[test]
+    fn binary_report_round_trip() {
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        let mut binary = Vec::new();
+        engine
+            .serialize_binary_report_to_writer(&mut binary)
+            .unwrap();
+        let mut csv = Vec::new();
+        engine.serialize_report_to_writer(&mut csv).unwrap();
+
+        let decoded = read_binary_report(binary.as_slice()).unwrap();
+        let expected = read_report(csv::Reader::from_reader(csv.as_slice())).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded, expected);
+
+        // Truncated record
+        assert!(read_binary_report(&binary[..binary.len() - 1]).is_err());
+
+        // Truncated length of the next record
+        let mut cut = binary.clone();
+        cut.extend_from_slice(&[1, 0]);
+        assert!(matches!(
+            read_binary_report(cut.as_slice()),
+            Err(EngineError::Reading(_))
+        ));
+
+        // Length claiming more than any row takes
+        let mut oversized = binary;
+        oversized.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read_binary_report(oversized.as_slice()),
+            Err(EngineError::Binary(_))
+        ));
+    }
+
+    #[test]
+    fn dedupe_per_operation() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            withdrawal, 1, 1, 2.0
+            deposit, 1, 1, 1.0
+            dispute, 1, 1,";
+
+        // Ids are shared by all operation types by default
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(engine.accounts[&1].available, dec!(0));
+        assert_eq!(engine.accounts[&1].held, dec!(5));
+
+        // The withdrawal reusing the deposit id is applied, the second deposit is
+        // still a duplicate. The dispute refers to the deposit, stored first.
+        let mut engine = super::Engine::with_config(EngineConfig {
+            dedupe_per_operation: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().skipped, 1);
+        assert_eq!(engine.accounts[&1].available, dec!(-2));
+        assert_eq!(engine.accounts[&1].held, dec!(5));
+        assert!(engine.accounts[&1].withdrawal_ids.contains(&1));
+
+        // Withdrawal first, a later deposit with the same id can't be disputed
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            withdrawal, 1, 2, 2.0
+            deposit, 1, 2, 1.0
+            dispute, 1, 2,";
+        let mut engine = super::Engine::with_config(EngineConfig {
+            dedupe_per_operation: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().applied, 4);
+        assert_eq!(engine.accounts[&1].available, dec!(4));
+        assert_eq!(engine.accounts[&1].held, dec!(2));
+        assert!(engine.accounts[&1].deposit_ids.contains(&2));
+    }
+
+    #[test]
+    fn dedupe_fingerprints() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            deposit, 1, 2, 1.0";
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            dedupe_fingerprints: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(buffer.clone()));
+        // Same file processed twice
+        process_csv(&mut engine, input);
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.accounts[&1].available, dec!(6));
+        assert_eq!(engine.stats().applied, 2);
+        assert_eq!(engine.stats().skipped, 2);
+        assert!(buffer
+            .contents()
+            .contains(&ProcessingError::DuplicateFingerprint(1).to_string()));
+
+        // Fingerprints are checked before the per-account id dedupe
+        assert_eq!(engine.error_counts.get("DuplicateFingerprint"), Some(&2));
+        assert_eq!(engine.error_counts.get("DuplicatedTransaction"), None);
+    }
+
+    #[test]
+    fn quiet_suppresses_progress_and_events() {
+        let log = SharedBuffer::default();
+        let events = EventBuffer::default();
+        let writer = events.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                quiet: true,
+                progress_interval: Some(1),
+                error_log_limit: Some(1),
+                ..Default::default()
+            });
+            engine.set_error_sink(Box::new(log.clone()));
+            process_csv(
+                &mut engine,
+                "type, client, tx, amount
+                deposit, 1, 1, 1.0
+                withdrawal, 1, 2, 2.0
+                withdrawal, 1, 3, 2.0",
+            );
+            assert_eq!(engine.error_counts.get("InsufficientFounds"), Some(&2));
+        });
+
+        assert_eq!(log.contents(), "");
+        assert!(events.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn quiet_suppresses_error_log() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            withdrawal, 1, 2, 5.0
+            dispute, 1, 9,
+            deposit, 1, 3, 2.0";
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            quiet: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(buffer.clone()));
+        process_csv(&mut engine, input);
+
+        assert_eq!(buffer.contents(), "");
+        assert_eq!(engine.stats().applied, 2);
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(engine.accounts[&1].available, dec!(3));
+
+        // Still collected by `run`
+        let mut engine = super::Engine::with_config(EngineConfig {
+            quiet: true,
+            ..Default::default()
+        });
+        let outcome = engine
+            .run(super::reader_builder().from_reader(input.as_bytes()))
+            .unwrap();
+        assert_eq!(outcome.errors.len(), 2);
+    }
+
+    #[test]
+    fn zip_archive_input() {
+        use std::io::{Cursor, Write};
+        use zip::write::SimpleFileOptions;
+
+        // Added out of order, entries are processed by name
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        archive.start_file("batch2.csv", options).unwrap();
+        archive
+            .write_all(b"type,client,tx,amount\ndispute,1,1,\n")
+            .unwrap();
+        archive.start_file("notes.txt", options).unwrap();
+        archive.write_all(b"not a csv").unwrap();
+        archive.start_file("batch1.csv", options).unwrap();
+        archive
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,5.0\n")
+            .unwrap();
+        let archive = archive.finish().unwrap();
+
+        let mut engine = super::Engine::new();
+        engine
+            .process_zip_from_reader(Cursor::new(archive.into_inner()))
+            .unwrap();
+
+        assert_eq!(engine.stats().read, 2);
+        assert_eq!(engine.stats().applied, 2);
+        assert_eq!(engine.accounts[&1].available, dec!(0));
+        assert_eq!(engine.accounts[&1].held, dec!(5));
+    }
+
+    #[test]
+    fn account_events() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            dispute, 1, 1,
+            resolve, 1, 1,";
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            record_events: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+
+        assert_eq!(
+            engine.account_events(1).unwrap(),
+            [AccountEvent::DisputeOpened(1), AccountEvent::Resolved(1)]
+        );
+        assert!(engine.account_events(2).is_none());
+
+        // Not recorded by default
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, input);
+        assert!(engine.account_events(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dispute_limit_per_transaction() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            dispute, 1, 1,
+            resolve, 1, 1,
+            dispute, 1, 1,
+            resolve, 1, 1,
+            dispute, 1, 1,
+            resolve, 1, 1,";
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            max_disputes_per_transaction: Some(2),
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(buffer.clone()));
+        process_csv(&mut engine, input);
+
+        // Third dispute is rejected, so is the resolve referencing it
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(engine.accounts[&1].available, dec!(5));
+        assert_eq!(engine.accounts[&1].held, dec!(0));
+        assert!(buffer
+            .contents()
+            .contains(&ProcessingError::DisputeLimitExceeded(1, 2).to_string()));
+
+        // Unbounded by default
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().skipped, 0);
+    }
+
+    #[test]
+    fn ndjson_input_matches_csv() {
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}
+
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "1.0"}
+{"type": "deposit", "client": 2, "tx": 3, "amount": "4"}
+"#;
+        let csv = "type, client, tx, amount
+            deposit, 1, 1, 2.5
+            withdrawal, 1, 2, 1.0
+            deposit, 2, 3, 4";
+
+        let mut from_ndjson = super::Engine::new();
+        from_ndjson
+            .process_ndjson_from_reader(ndjson.as_bytes())
+            .unwrap();
+        let mut from_csv = super::Engine::new();
+        process_csv(&mut from_csv, csv);
+
+        assert_eq!(from_ndjson.stats(), from_csv.stats());
+        assert_eq!(
+            from_ndjson.report_rows().unwrap(),
+            from_csv.report_rows().unwrap()
+        );
+    }
+
+    #[test]
+    fn ndjson_bad_line() {
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}
+{"type": "deposit", "client": 1
+{"type": "deposit", "client": 1, "tx": 2, "amount": "1"}"#;
+
+        let mut engine = super::Engine::new();
+        assert!(matches!(
+            engine.process_ndjson_from_reader(ndjson.as_bytes()),
+            Err(EngineError::JsonParsing(_))
+        ));
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(buffer.clone()));
+        engine
+            .process_ndjson_from_reader(ndjson.as_bytes())
+            .unwrap();
+
+        assert_eq!(engine.accounts[&1].available, dec!(3.5));
+        assert_eq!(engine.stats().skipped, 1);
+        assert!(buffer.contents().starts_with("Parsing error"));
+    }
+
+    #[test]
+    fn ndjson_rejects_lenient_amounts() {
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "$1,000.50"}"#;
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            lenient_amounts: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            engine.process_ndjson_from_reader(ndjson.as_bytes()),
+            Err(EngineError::Config(_))
+        ));
+        assert_eq!(engine.stats().read, 0);
+    }
+
+    #[test]
+    fn reject_dispute_amounts() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 5.0
+            dispute, 1, 1, 5.0
+            dispute, 1, 1,
+            resolve, 1, 1, 1.0";
+
+        // Ignored by default
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().applied, 3);
+        assert_eq!(engine.accounts[&1].held, dec!(0));
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            reject_dispute_amounts: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(buffer.clone()));
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.stats().applied, 2);
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(engine.accounts[&1].held, dec!(5));
+        assert!(buffer
+            .contents()
+            .contains(&ProcessingError::UnexpectedAmount(1).to_string()));
+    }
+
+    #[test]
+    fn pre_hook_rejects_large_withdrawals() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 500
+            withdrawal, 1, 2, 50
+            withdrawal, 1, 3, 150
+            deposit, 1, 4, 150";
+
+        let buffer = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(buffer.clone()));
+        engine.set_pre_hook(Box::new(|transaction, _| {
+            let large = transaction.amount.is_some_and(|amount| amount > dec!(100));
+            match transaction.operation == OperationType::Withdrawal && large {
+                true => HookDecision::Reject("withdrawal over 100".to_string()),
+                false => HookDecision::Allow,
+            }
+        }));
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.stats().applied, 3);
+        assert_eq!(engine.accounts[&1].available, dec!(600));
+        assert!(buffer.contents().contains(
+            &ProcessingError::RejectedByHook(3, "withdrawal over 100".to_string()).to_string()
+        ));
+    }
+
+    #[test]
+    fn resume_from_offset() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+dispute,1,1,
+deposit,3,3,3.0
+withdrawal,2,4,1.0
+resolve,1,1,
+";
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        std::fs::write(&path, input).unwrap();
+
+        let mut full = super::Engine::new();
+        full.process_input(&path).unwrap();
+
+        // Interrupted after the third record
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut engine = super::Engine::new();
+        engine.set_stop_flag(flag.clone());
+        let on_applied_flag = flag.clone();
+        engine.on_applied(Box::new(move |transaction, _| {
+            if transaction.operation == OperationType::Dispute {
+                on_applied_flag.store(true, Ordering::Relaxed);
+            }
+        }));
+        engine.process_input(&path).unwrap();
+        assert_eq!(engine.stats().read, 3);
+        let offset = engine.input_offset();
+        assert_eq!(&input[offset as usize..offset as usize + 7], "deposit");
+
+        flag.store(false, Ordering::Relaxed);
+        engine.process_input_from_offset(&path, offset).unwrap();
+
+        assert_eq!(engine.stats().read, 6);
+        assert_eq!(engine.stats().skipped, 0);
+        assert_eq!(engine.report_rows().unwrap(), full.report_rows().unwrap());
+        assert_eq!(engine.input_offset(), input.len() as u64);
+
+        // An offset inside a record continues with the next one
+        let mut engine = super::Engine::new();
+        engine.process_input_from_offset(&path, offset + 3).unwrap();
+        assert_eq!(engine.stats().read, 2);
+    }
+
+    #[test]
+    fn stop_flag_produces_partial_report() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut engine = super::Engine::new();
+        engine.set_stop_flag(flag.clone());
+
+        // Like a signal arriving while the second row is processed
+        let on_applied_flag = flag.clone();
+        engine.on_applied(Box::new(move |transaction, _| {
+            if transaction.id == 2 {
+                on_applied_flag.store(true, Ordering::Relaxed);
+            }
+        }));
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+        assert!(engine.is_stopped());
+        assert_eq!(engine.stats().read, 2);
+
+        let mut output = Vec::new();
+        engine.serialize_report_to_writer(&mut output).unwrap();
+        let rows = read_report(csv::Reader::from_reader(output.as_slice())).unwrap();
+        assert_eq!(
+            rows.iter().map(|row| row.client_id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn lifetime_totals() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            deposit, 1, 2, 2.5
+            withdrawal, 1, 3, 4.0
+            withdrawal, 1, 4, 1.5
+            withdrawal, 1, 5, 100
+            dispute, 1, 2,
+            chargeback, 1, 2,
+            dispute, 1, 3,
+            deposit, 2, 6, 1";
+
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(&mut engine, input);
+
+        // Disputes and the chargeback don't change them, the failed withdrawal isn't counted
+        let totals = engine.lifetime_totals(1).unwrap();
+        assert_eq!(totals.deposited, dec!(12.5));
+        assert_eq!(totals.withdrawn, dec!(5.5));
+        assert_eq!(totals.net_position(), dec!(7));
+        assert_eq!(engine.accounts[&1].available, dec!(4.5));
+
+        assert_eq!(engine.lifetime_totals(2).unwrap().net_position(), dec!(1));
+        assert_eq!(engine.lifetime_totals(3), None);
+    }
+
+    #[test]
+    fn unbacked_withdrawal_dispute_warning() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            withdrawal, 1, 2, 4.0
+            dispute, 1, 1,
+            resolve, 1, 1,
+            dispute, 1, 2,";
+
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, input);
+        assert!(engine.warnings().is_empty());
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            warn_unbacked_disputes: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.accounts[&1].total_deposited, dec!(10));
+        assert_eq!(engine.accounts[&1].total_withdrawn, dec!(4));
+        assert_eq!(
+            engine.warnings(),
+            &[Warning::UnbackedWithdrawalDispute {
+                client_id: 1,
+                tx: 2,
+                total: dec!(10),
+                net_deposits: dec!(6),
+            }]
+        );
+    }
+
+    #[test]
+    fn report_flush_interval() {
+        // Counts flushes reaching the underlying writer
+        #[derive(Clone, Default)]
+        struct FlushCounter {
+            output: SharedBuffer,
+            flushes: Rc<RefCell<usize>>,
+        }
+
+        impl std::io::Write for FlushCounter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                *self.flushes.borrow_mut() += 1;
+                Ok(())
+            }
+        }
+
+        let report = |flush_interval| {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                report_flush_interval: flush_interval,
+                ..Default::default()
+            });
+            process_csv(&mut engine, MULTI_CLIENT_INPUT);
+
+            let writer = FlushCounter::default();
+            engine.serialize_report_to_writer(writer.clone()).unwrap();
+            let flushes = *writer.flushes.borrow();
+            (writer.output.contents(), flushes)
+        };
+
+        // Writer flushes at the end (and when dropped) anyway
+        let (expected, flushes) = report(None);
+
+        // 3 accounts, so flushed additionally after the 2nd row, or after each
+        assert_eq!(report(Some(2)), (expected.clone(), flushes + 1));
+        assert_eq!(report(Some(1)), (expected, flushes + 3));
+    }
+
+    #[test]
+    fn operation_type_case_insensitive() {
+        for name in ["DEPOSIT", "Deposit", "deposit", " DePoSiT "] {
+            let operation: OperationType = serde_json::from_str(&format!("\"{name}\"")).unwrap();
+            assert_eq!(operation, OperationType::Deposit);
+        }
+        assert!(serde_json::from_str::<OperationType>("\"deposits\"").is_err());
+
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            DEPOSIT, 1, 1, 1.0
+            Deposit, 1, 2, 1.0
+            deposit, 1, 3, 1.0
+            Withdrawal, 1, 4, 0.5",
+        );
+        assert_eq!(engine.accounts[&1].available, dec!(2.5));
+    }
+
+    #[test]
+    fn locked_clients_after_chargeback() {
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 3, 1, 1.0
+            deposit, 2, 2, 1.0
+            deposit, 1, 3, 1.0
+            dispute, 2, 2,
+            dispute, 1, 3,
+            chargeback, 2, 2,",
+        );
+
+        assert_eq!(engine.locked_clients(), vec![2]);
+
+        engine
+            .process_one(transaction(3, OperationType::Chargeback, 1, None))
+            .unwrap();
+        assert_eq!(engine.locked_clients(), vec![1, 2]);
+    }
+
+    #[test]
+    fn out_of_range_amount_is_skipped() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 99999999999999999999999999999
+            deposit, 1, 2, 1.5";
+
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(&mut engine, input);
+
+        assert_eq!(engine.accounts[&1].available, dec!(1.5));
+        assert_eq!(engine.stats().skipped, 1);
+        let logged = errors.contents();
+        assert!(logged.contains("line: 2"), "{logged}");
+        assert!(
+            logged.contains("invalid amount `99999999999999999999999999999`"),
+            "{logged}"
+        );
+
+        // Without skipping it's still an error, not a panic
+        let mut engine = super::Engine::new();
+        let reader = super::reader_builder().from_reader(input.as_bytes());
+        assert!(matches!(
+            engine.process_from_reader(reader),
+            Err(EngineError::Parsing(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_client_is_skipped() {
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, , 1, 1.0
+            deposit, abc, 2, 1.0
+            deposit
+            deposit, 1, 3, 2.0",
+        );
+
+        assert_eq!(engine.stats().skipped, 3);
+        assert_eq!(engine.stats().applied, 1);
+        assert_eq!(engine.accounts[&1].available, dec!(2));
+        assert_eq!(
+            errors.contents(),
+            "Processing error: Invalid client id in row `deposit,,1,1.0`\n\
+            Processing error: Invalid client id in row `deposit,abc,2,1.0`\n\
+            Processing error: Invalid client id in row `deposit`\n"
+        );
+    }
+
+    #[test]
+    fn replay_log_reproduces_report() {
+        let config = EngineConfig {
+            amounts_in_minor_units: true,
+            ..Default::default()
+        };
+        let log = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(config.clone());
+        engine.set_error_sink(Box::new(std::io::sink()));
+        engine.set_replay_log(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount, timestamp
+            Deposit, 1, 1, 15000, 2024-01-01T00:00:00Z
+            deposit, 2, 2, 20000,
+            withdrawal, 1, 3, 2500,
+            withdrawal, 2, 4, 99999,
+            dispute, 2, 2,
+            chargeback, 2, 2, 5000
+            query, 1",
+        );
+
+        let mut replayed = super::Engine::with_config(EngineConfig {
+            amounts_in_minor_units: false,
+            ..config
+        });
+        process_csv(&mut replayed, &log.contents());
+
+        assert_eq!(replayed.stats().applied, engine.stats().applied);
+        assert_eq!(replayed.accounts, engine.accounts);
+        assert_eq!(replayed.accounts[&2].available, dec!(1.5));
+        assert!(log
+            .contents()
+            .starts_with("type,client,tx,amount,timestamp,currency\n"));
+    }
+
+    #[test]
+    fn report_total_overflow() {
+        let max = rust_decimal::Decimal::MAX;
+        let mut engine = super::Engine::new();
+        let operations = [
+            (1, OperationType::Deposit, Some(max)),
+            (2, OperationType::Withdrawal, Some(max)),
+            (2, OperationType::Dispute, None),
+            (3, OperationType::Deposit, Some(max)),
+        ];
+        for (id, operation, amount) in operations {
+            engine
+                .process_one(transaction(id, operation, 1, amount))
+                .unwrap();
+        }
+        assert_eq!(engine.accounts[&1].available, max);
+        assert_eq!(engine.accounts[&1].held, max);
+
+        let result = engine.serialize_report_to_writer(vec![]);
+
+        assert!(matches!(
+            result,
+            Err(EngineError::Processing(ProcessingError::Overflow(None)))
+        ));
+    }
+
+    #[test]
+    fn lenient_resolves() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            dispute, 1, 1,
+            resolve, 1, 1,
+            resolve, 1, 1,
+            resolve, 1, 2,";
+
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().skipped, 2);
+        assert_eq!(
+            errors.contents(),
+            "Processing error: Resolve called on not disputed operation `Resolve` for transaction `1`\n\
+            Processing error: Referenced transaction `2` doesn't exist\n"
+        );
+
+        // Missing transactions are still rejected
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            lenient_resolves: true,
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(&mut engine, input);
+        assert_eq!(engine.stats().applied, 4);
+        assert_eq!(engine.stats().skipped, 1);
+        assert_eq!(
+            errors.contents(),
+            "Processing error: Referenced transaction `2` doesn't exist\n"
+        );
+        assert_eq!(engine.accounts[&1].available, dec!(1));
+    }
+
+    #[test]
+    fn run_returns_outcome() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            ..Default::default()
+        });
+        let reader = super::reader_builder().from_reader(
+            "type, client, tx, amount
+            deposit, 1, 1, 2.0
+            refund, 1, 2, 1.0
+            withdrawal, 1, 3, 5.0
+            deposit, 2, 4, 1.0"
+                .as_bytes(),
+        );
+
+        let outcome = engine.run(reader).unwrap();
+
+        let rows: Vec<_> = outcome
+            .rows
+            .iter()
+            .map(|row| (row.client_id, row.available, row.total, row.locked))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![(1, dec!(2), dec!(2), false), (2, dec!(1), dec!(1), false)]
+        );
+
+        let errors: Vec<_> = outcome
+            .errors
+            .iter()
+            .map(|error| (error.error_type, error.line, error.tx))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("Parsing", Some(3), None),
+                ("InsufficientFounds", Some(4), Some(3))
+            ]
+        );
+
+        assert_eq!(
+            outcome.stats,
+            ProcessingStats {
+                read: 4,
+                applied: 2,
+                skipped: 2,
+                filtered: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn run_to_writer_matches_report() {
+        let input = "type, client, tx, amount
+            deposit, 2, 1, 2.0
+            withdrawal, 2, 2, 5.0
+            deposit, 1, 3, 1.5";
+        let config = EngineConfig {
+            report_sort: Some(SortColumn::Available),
+            report_totals: true,
+            progress_interval: Some(1),
+            ..Default::default()
+        };
+
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(config.clone());
+        engine.set_error_sink(Box::new(errors.clone()));
+        let mut output = Vec::new();
+        let outcome = engine
+            .run_to_writer(
+                super::reader_builder().from_reader(input.as_bytes()),
+                &mut output,
+            )
+            .unwrap();
+
+        assert_eq!(outcome.errors.len(), 1);
+        assert_eq!(errors.contents(), "");
+
+        let mut expected = super::Engine::with_config(config);
+        expected.set_error_sink(Box::new(SharedBuffer::default()));
+        process_csv(&mut expected, input);
+        let mut report = Vec::new();
+        expected.serialize_report_to_writer(&mut report).unwrap();
+        assert_eq!(String::from_utf8(output), String::from_utf8(report));
+    }
+
+    #[test]
+    fn clamped_dispute_of_withdrawn_deposit() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            withdrawal, 1, 2, 7.0
+            dispute, 1, 1,";
+
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, input);
+        assert_eq!(engine.accounts[&1].available, dec!(-7));
+        assert!(engine.warnings().is_empty());
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            clamp_available_on_dispute: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(3),
+                under_dispute: HashSet::from_iter(vec![1]),
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+        assert_eq!(
+            engine.warnings(),
+            &[Warning::PartialHold {
+                client_id: 1,
+                tx: 1,
+                shortfall: dec!(7),
+            }]
+        );
+        assert_eq!(engine.verify_invariants(), Ok(()));
+
+        // Only the held part goes back
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            resolve, 1, 1,",
+        );
+        assert_eq!(
+            &AccountData {
+                available: dec!(3),
+                held: dec!(0),
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+        assert!(engine.accounts[&1].partial_holds.is_empty());
+    }
+
+    #[test]
+    fn verify_report() {
+        let mut engine = super::Engine::new();
+        process_csv(&mut engine, MULTI_CLIENT_INPUT);
+        let actual = engine.report_rows().unwrap();
+
+        let read = |report: &str| {
+            read_report(super::reader_builder().from_reader(report.as_bytes())).unwrap()
+        };
+
+        // Different order, trailing zeros and a totals footer
+        let matching = read(
+            "client,available,held,total,locked
+            3,3.00,0,3,false
+            1,1.0,0.0,1.0,false
+            2,1,0,1,false
+            ,5,0,5,",
+        );
+        assert!(diff_reports(&matching, &actual).is_empty());
+
+        let mismatched = read(
+            "client,available,held,total,locked
+            1,1,0,1,false
+            2,2,0,2,false
+            4,1,0,1,true",
+        );
+        assert_eq!(
+            diff_reports(&mismatched, &actual),
+            vec![
+                "- 2,2,0,2,false",
+                "+ 2,1,0,1,false",
+                "+ 3,3,0,3,false",
+                "- 4,1,0,1,true",
+            ]
+        );
+    }
+
+    #[test]
+    fn max_and_missing_transaction_ids() {
+        let mut engine = super::Engine::new();
+        assert_eq!(engine.max_transaction_id(), None);
+        assert!(engine.missing_transaction_ids().is_empty());
+
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, 1.0
+            dispute, 1, 1,
+            withdrawal, 2, 5, 0.5
+            withdrawal, 2, 8, 5.0",
+        );
+
+        assert_eq!(engine.max_transaction_id(), Some(8));
+        // Rejected withdrawal 8 is missing too
+        assert_eq!(engine.missing_transaction_ids(), vec![3..=4, 6..=8]);
+    }
+
+    #[test]
+    fn normalized_amounts() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, 1.00
+            deposit, 3, 3, 0.50
+            deposit, 3, 4, 0.5";
+        let report = |normalize_amounts| {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                normalize_amounts,
+                ..Default::default()
+            });
+            process_csv(&mut engine, input);
+            let output = SharedBuffer::default();
+            engine.serialize_report_to_writer(output.clone()).unwrap();
+            output.contents()
+        };
+
+        assert_eq!(
+            report(true),
+            "client,available,held,total,locked\n1,1,0,1,false\n2,1,0,1,false\n3,1,0,1,false\n"
+        );
+        assert_eq!(
+            report(false),
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n2,1.00,0,1.00,false\n3,1.00,0,1.00,false\n"
+        );
+    }
+
+    #[test]
+    fn disputes_settled_on_locked_account() {
+        let errors = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(errors.clone()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 1, 2, 2.0
+            deposit, 1, 3, 4.0
+            dispute, 1, 1,
+            dispute, 1, 2,
+            dispute, 1, 3,
+            chargeback, 1, 1,
+            resolve, 1, 2,
+            chargeback, 1, 3,
+            deposit, 1, 4, 1.0
+            dispute, 1, 2,",
+        );
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(2),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&1).unwrap()
+        );
+        assert_eq!(
+            errors.contents(),
+            "Processing error: Account `1` is locked\nProcessing error: Account `1` is locked\n"
+        );
+    }
+
+    #[test]
+    fn metrics_text() {
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(
+            &mut engine,
+            "type, client, tx, amount
+            deposit, 1, 1, 1.0
+            withdrawal, 1, 2, 5.0
+            withdrawal, 1, 3, 5.0
+            deposit, 2, 4, 1.0
+            dispute, 2, 4,
+            chargeback, 2, 4,
+            resolve, 2, 9,",
+        );
+
+        let metrics = engine.metrics_text();
+        for line in [
+            "# TYPE transponster_records_read_total counter",
+            "transponster_records_read_total 7",
+            "transponster_records_applied_total 4",
+            "transponster_records_skipped_total 3",
+            "transponster_errors_total{type=\"InsufficientFounds\"} 2",
+            "transponster_errors_total{type=\"MissingTransaction\"} 1",
+            "# TYPE transponster_accounts gauge",
+            "transponster_accounts 2",
+            "transponster_locked_accounts 1",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing `{line}`");
+        }
+    }
+
+    #[test]
+    fn no_deposit_on_locked_account() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+            transaction(2, OperationType::Deposit, 10, Some(dec!(2))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_after_unlock() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| engine.process_one(t).unwrap());
+
+        assert!(engine.unlock_account(10));
+        assert!(!engine.unlock_account(11));
+
+        engine
+            .process_one(transaction(2, OperationType::Deposit, 10, Some(dec!(3))))
+            .unwrap();
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(3),
+                held: dec!(0),
+                locked: false,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn no_withdrawal_on_locked_account() {
+        let transactions: Vec<Transaction> = vec![
+            transaction(1, OperationType::Deposit, 10, Some(dec!(2))),
+            transaction(1, OperationType::Dispute, 10, None),
+            transaction(1, OperationType::Chargeback, 10, None),
+            transaction(2, OperationType::Withdrawal, 10, Some(dec!(2))),
+        ];
+
+        let mut engine = super::Engine::new();
+        transactions
+            .into_iter()
+            .for_each(|t| _ = engine.process_one(t));
+
+        assert_eq!(
+            &AccountData {
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+                ..Default::default()
+            },
+            engine.accounts.get(&10).unwrap()
+        );
+    }
+
+    #[test]
+    fn integer_amounts_match_decimal_report() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10.5\n\
+            deposit,1,2,0.0001\n\
+            withdrawal,1,3,3.25\n\
+            dispute,1,1,\n\
+            deposit,2,4,7\n\
+            withdrawal,2,5,2\n\
+            dispute,2,5,\n\
+            chargeback,2,5,1\n\
+            resolve,1,1,\n";
+
+        let mut decimal = super::Engine::new();
+        let expected = decimal
+            .run(super::reader_builder().from_reader(input.as_bytes()))
+            .unwrap();
+
+        let mut minor_units =
+            super::Engine::<MinorUnits>::with_amount_config(EngineConfig::default());
+        let outcome = minor_units
+            .run(super::reader_builder().from_reader(input.as_bytes()))
+            .unwrap();
+
+        assert_eq!(outcome.rows, expected.rows);
+        assert_eq!(
+            minor_units.accounts.get(&1).unwrap().available,
+            MinorUnits(72_501)
+        );
+
+        // Too precise for 1/10000 units
+        let precise = "type,client,tx,amount\ndeposit,1,1,0.00001\n";
+        let mut minor_units = super::Engine::<MinorUnits>::default();
+        assert!(minor_units
+            .run(super::reader_builder().from_reader(precise.as_bytes()))
+            .is_err());
+    }
+
+    #[test]
+    fn report_quote_style_always() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            report_quote_style: QuoteStyle::Always,
+            ..Default::default()
+        });
+        process_csv(&mut engine, "type,client,tx,amount\ndeposit,1,1,1.5\n");
+
+        let mut output = Vec::new();
+        engine.serialize_report_to_writer(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"1.5\",\"0\",\"1.5\",\"false\"\n"
+        );
+    }
+
+    #[test]
+    fn reference_counts_split_disputed_transactions() {
+        let mut engine = super::Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,1,2,3\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n",
+        );
+
+        assert_eq!(
+            engine.reference_counts(1),
+            Some(ReferenceCounts {
+                referenced: 1,
+                unreferenced: 1,
+            })
+        );
+        assert_eq!(engine.reference_counts(2), None);
     }
 
     #[test]
-    fn two_deposits() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-        ];
+    fn watch_picks_up_appended_records() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1\n").unwrap();
 
+        let flag = Arc::new(AtomicBool::new(false));
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        engine.set_stop_flag(flag.clone());
+
+        // Every applied record appends more input, the first append ends mid-line
+        let appended_path = path.clone();
+        engine.on_applied(Box::new(move |transaction, _| {
+            let appended = match transaction.id {
+                1 => "deposit,1,2,2\nwithdr",
+                2 => "awal,1,3,1.5\n",
+                _ => return flag.store(true, Ordering::Relaxed),
+            };
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&appended_path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, appended.as_bytes()).unwrap();
+        }));
+
+        engine
+            .watch_input(&path, std::time::Duration::from_millis(1))
+            .unwrap();
 
+        assert_eq!(engine.stats().read, 3);
+        assert_eq!(engine.stats().skipped, 0);
+        assert_eq!(engine.accounts.get(&1).unwrap().available, dec!(1.5));
         assert_eq!(
-            &AccountData {
-                available: dec!(2),
-                held: dec!(0),
-                locked: false,
+            engine.input_offset(),
+            std::fs::metadata(&path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn verify_no_negative_held() {
+        let config = EngineConfig {
+            verify_no_negative_held: true,
+            ..Default::default()
+        };
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            dispute,1,1,\n\
+            resolve,1,1,\n\
+            deposit,2,2,3\n\
+            dispute,2,2,\n\
+            chargeback,2,2,\n";
+
+        let mut engine = super::Engine::with_config(config.clone());
+        process_csv(&mut engine, input);
+
+        // Forced bad state, the checked operations never produce it
+        let mut engine = super::Engine::with_config(config);
+        engine.accounts.insert(
+            3,
+            AccountData {
+                held: dec!(-1),
                 ..Default::default()
             },
-            engine.accounts.get(&10).unwrap()
         );
+        let result =
+            engine.process_from_reader(super::reader_builder().from_reader(input.as_bytes()));
+
+        assert!(matches!(
+            result,
+            Err(EngineError::NegativeHeld(3, held)) if held == dec!(-1)
+        ));
     }
 
     #[test]
-    fn two_withdrawals() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-        ];
+    fn report_sorted_by_total() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            report_sort: Some(SortColumn::Total),
+            ..Default::default()
+        });
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,2\n\
+             deposit,2,2,7\n\
+             deposit,3,3,5\n\
+             deposit,4,4,5\n",
+        );
+
+        let clients: Vec<ClientId> = engine
+            .report_rows()
+            .unwrap()
+            .iter()
+            .map(|row| row.client_id)
+            .collect();
+        assert_eq!(clients, vec![2, 3, 4, 1]);
+    }
+
+    #[test]
+    fn missing_amount_of_row_and_of_disputed_transaction() {
+        let row = |operation, amount| transaction(1, operation, 10, amount);
 
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        assert_eq!(
+            engine.process_one(row(OperationType::Deposit, None)),
+            Err(ProcessingError::MissingAmount(1))
+        );
+        assert_eq!(
+            engine.process_one(row(OperationType::Withdrawal, None)),
+            Err(ProcessingError::MissingAmount(1))
+        );
 
+        // Forced bad state, a stored deposit always has an amount
+        engine
+            .accounts
+            .get_mut(&10)
+            .unwrap()
+            .transactions
+            .insert(1, row(OperationType::Deposit, None));
         assert_eq!(
-            &AccountData {
-                available: dec!(0),
-                held: dec!(0),
-                locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            engine.process_one(row(OperationType::Dispute, None)),
+            Err(ProcessingError::CorruptDisputedAmount(1))
         );
     }
 
     #[test]
-    fn deposit_withdraw_balance_positive() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(0.5)),
-            },
-        ];
+    fn error_log_limit_suppresses_repeated_errors() {
+        let log = SharedBuffer::default();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            error_log_limit: Some(2),
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(log.clone()));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             withdrawal,1,1,1\n\
+             withdrawal,1,2,1\n\
+             withdrawal,1,3,1\n\
+             withdrawal,1,4,1\n\
+             withdrawal,1,5,1\n\
+             dispute,1,9,\n",
+        );
+
+        assert_eq!(
+            log.contents(),
+            "Processing error: insufficient founds for transaction `1`; account: `1`\n\
+             Processing error: insufficient founds for transaction `2`; account: `1`\n\
+             Processing error: Referenced transaction `9` doesn't exist\n\
+             Suppressed 3 more `InsufficientFounds` errors\n"
+        );
+        assert_eq!(engine.error_counts.get("InsufficientFounds"), Some(&5));
+    }
+
+    #[test]
+    fn dispute_before_transaction_with_prescan() {
+        // Client 2 can't dispute the later transaction of client 1
+        let input = "type,client,tx,amount
+dispute,1,1,
+dispute,1,7,
+dispute,2,1,
+deposit,1,1,5
+dispute,1,1,
+";
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        std::fs::write(&path, input).unwrap();
 
+        let mut engine = super::Engine::with_config(EngineConfig {
+            prescan_transaction_ids: true,
+            ..Default::default()
+        });
+        engine.process_input(&path).unwrap();
+
+        assert_eq!(
+            engine.error_counts.get("DisputeBeforeTransaction"),
+            Some(&1)
+        );
+        assert_eq!(engine.error_counts.get("MissingTransaction"), Some(&2));
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec!(5));
+
+        // Without the pre-scan all are missing transactions
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        engine.process_input(&path).unwrap();
+        assert_eq!(engine.error_counts.get("MissingTransaction"), Some(&3));
+    }
+
+    #[test]
+    fn prescan_covers_all_zip_entries() {
+        use std::io::{Cursor, Write};
+        use zip::write::SimpleFileOptions;
+
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        archive.start_file("batch1.csv", options).unwrap();
+        archive
+            .write_all(b"type,client,tx,amount\ndispute,1,1,\n")
+            .unwrap();
+        archive.start_file("batch2.csv", options).unwrap();
+        archive
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,5.0\n")
+            .unwrap();
+        let archive = archive.finish().unwrap().into_inner();
+
+        let mut engine = super::Engine::with_config(EngineConfig {
+            prescan_transaction_ids: true,
+            ..Default::default()
+        });
+        engine
+            .process_zip_from_reader(Cursor::new(archive))
+            .unwrap();
 
         assert_eq!(
-            &AccountData {
-                available: dec!(0.5),
-                held: dec!(0),
-                locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            engine.error_counts.get("DisputeBeforeTransaction"),
+            Some(&1)
         );
+        assert!(engine.upcoming_ids.is_empty());
     }
 
     #[test]
-    fn deposit_withdraw_balance_negative() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1.5)),
-            },
+    fn report_metadata_precedes_header() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            report_metadata: true,
+            ..Default::default()
+        });
+        process_csv(&mut engine, "type,client,tx,amount\ndeposit,1,1,1.5\n");
+
+        let mut output = Vec::new();
+        engine.serialize_report_to_writer(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        let prefix = format!(
+            "# transponster v{} generated at ",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(lines[0].starts_with(&prefix));
+        assert_eq!(
+            lines[1..],
+            ["client,available,held,total,locked", "1,1.5,0,1.5,false"]
+        );
+    }
+
+    #[test]
+    fn process_iter_matches_csv() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            withdrawal,1,2,7\n\
+            deposit,2,3,2\n\
+            dispute,2,3,\n\
+            chargeback,2,3,\n";
+        let mut csv_engine = super::Engine::new();
+        process_csv(&mut csv_engine, input);
+
+        let transactions = vec![
+            transaction(1, OperationType::Deposit, 1, Some(dec!(5))),
+            transaction(2, OperationType::Withdrawal, 1, Some(dec!(7))),
+            transaction(3, OperationType::Deposit, 2, Some(dec!(2))),
+            transaction(3, OperationType::Dispute, 2, None),
+            transaction(3, OperationType::Chargeback, 2, None),
         ];
+        let mut engine = super::Engine::new();
+        engine.process_iter(transactions).unwrap();
+
+        assert_eq!(
+            engine.report_rows().unwrap(),
+            csv_engine.report_rows().unwrap()
+        );
+        assert_eq!(engine.stats(), csv_engine.stats());
+        assert_eq!(engine.error_counts, csv_engine.error_counts);
+    }
+
+    #[test]
+    fn evict_locked_accounts_keeps_balances() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            evict_locked_after: Some(2),
+            ..Default::default()
+        });
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,1,2,3\n\
+             dispute,1,1,\n\
+             dispute,1,2,\n\
+             chargeback,1,1,\n\
+             deposit,2,3,1\n",
+        );
+
+        // Locked one record ago, not evicted yet
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.transactions.len(), 2);
+        let report = engine.report_rows().unwrap();
+
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\ndeposit,2,4,1\nresolve,1,2,\n",
+        );
+
+        let account = engine.accounts.get(&1).unwrap();
+        assert!(account.transactions.is_empty());
+        assert!(account.under_dispute.is_empty());
+        assert_eq!(account.held, dec!(3));
+        assert_eq!(engine.report_rows().unwrap()[0], report[0]);
+        assert_eq!(engine.verify_invariants(), Ok(()));
+        // The open dispute is gone with the history
+        assert_eq!(engine.error_counts.get("MissingTransaction"), Some(&1));
+    }
+
+    #[test]
+    fn unlocked_account_keeps_history() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            evict_locked_after: Some(1),
+            ..Default::default()
+        });
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,1,2,3\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n",
+        );
+        assert!(engine.unlock_account(1));
+
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\ndeposit,2,3,1\ndeposit,2,4,1\ndispute,1,2,\n",
+        );
+
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!((account.available, account.held), (dec!(0), dec!(3)));
+        assert_eq!(engine.error_counts.get("MissingTransaction"), None);
+    }
 
+    #[test]
+    fn open_creates_empty_account() {
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        process_csv(&mut engine, "type,client,tx,amount\nopen,5,,\nopen,5,,\n");
 
+        assert_eq!(engine.stats().applied, 2);
         assert_eq!(
-            &AccountData {
-                available: dec!(1),
+            engine.report_rows().unwrap(),
+            vec![ReportRow {
+                client_id: 5,
+                available: dec!(0),
                 held: dec!(0),
+                total: dec!(0),
                 locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+                disputed: None,
+                dust: None,
+            }]
         );
     }
 
     #[test]
-    fn deposit_and_dispute() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn totals_overflow_is_an_error() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            report_totals: true,
+            ..Default::default()
+        });
+        let large = rust_decimal::Decimal::MAX / dec!(4);
+        for client_id in 0..1000 {
+            engine.accounts.insert(
+                client_id,
+                AccountData {
+                    available: large,
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert!(matches!(
+            engine.totals(),
+            Err(EngineError::Processing(ProcessingError::Overflow(None)))
+        ));
+        assert!(matches!(
+            engine.report_rows(),
+            Err(EngineError::Processing(ProcessingError::Overflow(None)))
+        ));
+    }
 
+    #[cfg(feature = "wide-ids")]
+    #[test]
+    fn wide_client_and_transaction_ids() {
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\ndeposit,70000,5000000000,2.5\n",
+        );
+
+        assert_eq!(engine.stats().applied, 1);
+        assert_eq!(engine.report_rows().unwrap()[0].client_id, 70000);
+    }
+
+    #[test]
+    fn merge_engines() {
+        let mut first = super::Engine::new();
+        process_csv(
+            &mut first,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,2,2,3\n\
+             dispute,2,2,\n",
+        );
+        let mut second = super::Engine::new();
+        process_csv(
+            &mut second,
+            "type,client,tx,amount\n\
+             deposit,2,3,1\n\
+             withdrawal,2,4,0.5\n\
+             deposit,3,5,7\n",
+        );
+
+        first.merge(second).unwrap();
 
+        let mut expected = super::Engine::new();
+        process_csv(
+            &mut expected,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,2,2,3\n\
+             dispute,2,2,\n\
+             deposit,2,3,1\n\
+             withdrawal,2,4,0.5\n\
+             deposit,3,5,7\n",
+        );
         assert_eq!(
-            &AccountData {
-                available: dec!(0),
-                held: dec!(1),
-                locked: false,
-                under_dispute: HashSet::from_iter(vec![1]),
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            first.report_rows().unwrap(),
+            expected.report_rows().unwrap()
+        );
+        assert_eq!(first.stats(), expected.stats());
+        assert_eq!(first.accounts.get(&2).unwrap().transactions.len(), 3);
+        assert_eq!(first.verify_invariants(), Ok(()));
+
+        // Same id with a different amount
+        let mut conflicting = super::Engine::new();
+        process_csv(&mut conflicting, "type,client,tx,amount\ndeposit,2,3,9\n");
+        let result = first.merge(conflicting);
+        assert!(matches!(result, Err(EngineError::MergeConflict(2, 3))));
+        assert_eq!(
+            first.report_rows().unwrap(),
+            expected.report_rows().unwrap()
+        );
+
+        // The same transaction in both shards would be counted twice
+        let mut duplicate = super::Engine::new();
+        process_csv(&mut duplicate, "type,client,tx,amount\ndeposit,2,3,1\n");
+        let result = first.merge(duplicate);
+        assert!(matches!(result, Err(EngineError::MergeConflict(2, 3))));
+        assert_eq!(first.accounts.get(&2).unwrap().available, dec!(0.5));
+        assert_eq!(
+            first.report_rows().unwrap(),
+            expected.report_rows().unwrap()
         );
     }
 
     #[test]
-    fn deposit_withdrawal_and_dispute_deposit() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(3)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn merge_conflicts_on_unstored_ids() {
+        let config = EngineConfig {
+            store_withdrawals_for_dispute: false,
+            ..Default::default()
+        };
+        let engine = |input: &str| {
+            let mut engine = super::Engine::with_config(config.clone());
+            process_csv(&mut engine, input);
+            engine
+        };
+        let withdrawn = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,1\n";
+
+        // A withdrawal id used by a deposit of the other shard
+        let mut first = engine(withdrawn);
+        let result = first.merge(engine("type,client,tx,amount\ndeposit,1,2,1\n"));
+        assert!(matches!(result, Err(EngineError::MergeConflict(1, 2))));
+
+        // Withdrawals with the same id, amounts can't be compared
+        let result = first.merge(engine(
+            "type,client,tx,amount\ndeposit,1,3,5\nwithdrawal,1,2,4\n",
+        ));
+        assert!(matches!(result, Err(EngineError::MergeConflict(1, 2))));
+        assert_eq!(first.accounts.get(&1).unwrap().available, dec!(4));
+
+        // Deposits and withdrawals may share ids when deduplicating per operation
+        let mut first = super::Engine::with_config(EngineConfig {
+            dedupe_per_operation: true,
+            ..config.clone()
+        });
+        process_csv(&mut first, withdrawn);
+        assert!(first
+            .merge(engine("type,client,tx,amount\ndeposit,1,2,1\n"))
+            .is_ok());
+        assert_eq!(first.accounts.get(&1).unwrap().available, dec!(5));
+    }
+
+    #[test]
+    fn merge_continues_eviction_of_locked_accounts() {
+        let config = EngineConfig {
+            evict_locked_after: Some(2),
+            ..Default::default()
+        };
+        let mut first = super::Engine::with_config(config.clone());
+        process_csv(&mut first, "type,client,tx,amount\ndeposit,1,1,5\n");
+        let mut second = super::Engine::with_config(config);
+        process_csv(
+            &mut second,
+            "type,client,tx,amount\n\
+             deposit,2,2,3\n\
+             dispute,2,2,\n\
+             chargeback,2,2,\n",
+        );
+
+        first.merge(second).unwrap();
+        process_csv(&mut first, "type,client,tx,amount\ndeposit,1,3,1\n");
+        assert_eq!(first.accounts.get(&2).unwrap().transactions.len(), 1);
+
+        process_csv(&mut first, "type,client,tx,amount\ndeposit,1,4,1\n");
+        assert!(first.accounts.get(&2).unwrap().transactions.is_empty());
+    }
 
+    #[test]
+    fn reconcile_accounts_flags_inconsistent_total() {
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             withdrawal,1,2,4\n\
+             dispute,1,2,\n\
+             resolve,1,2,\n\
+             deposit,1,3,3\n\
+             dispute,1,3,\n\
+             chargeback,1,3,1\n\
+             deposit,2,4,5\n",
+        );
+
+        engine.reconcile_accounts();
+        assert!(engine.warnings().is_empty());
+
+        let mut generated = super::Engine::new();
+        generated
+            .process_iter(generate_transactions(5_000, 7))
+            .unwrap();
+        generated.reconcile_accounts();
+        assert!(generated.warnings().is_empty());
 
+        // Forced bad state, funds appearing without any transaction
+        engine.accounts.get_mut(&2).unwrap().available = dec!(6);
+        engine.reconcile_accounts();
         assert_eq!(
-            &AccountData {
-                available: dec!(-2),
-                held: dec!(3),
-                locked: false,
-                under_dispute: HashSet::from_iter(vec![1]),
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            engine.warnings(),
+            [Warning::ReconciliationMismatch {
+                client_id: 2,
+                total: dec!(6),
+                expected: dec!(5),
+            }]
         );
     }
 
     #[test]
-    fn deposit_dispute_and_resolve_deposit() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Resolve,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn lenient_amounts_strip_symbol_and_separators() {
+        let input = "type,client,tx,amount\ndeposit,1,1,\"$1,000.50\"\ndeposit,1,2,€ 2\n";
+        let lenient = EngineConfig {
+            lenient_amounts: true,
+            ..Default::default()
+        };
+
+        let mut engine = super::Engine::with_config(lenient.clone());
+        process_csv(&mut engine, input);
+        assert_eq!(engine.accounts.get(&1).unwrap().available, dec!(1002.50));
+
+        let mut engine = super::Engine::new();
+        let result =
+            engine.process_from_reader(super::reader_builder().from_reader(input.as_bytes()));
+        assert!(matches!(result, Err(EngineError::Parsing(_))));
+
+        // A decimal comma or misplaced separators are ambiguous
+        for ambiguous in ["\"$1,00\"", "\"1,0000.5\"", "\"$$1\"", "\"1.000,5\""] {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,{ambiguous}\n");
+            let mut engine = super::Engine::with_config(lenient.clone());
+            let result =
+                engine.process_from_reader(super::reader_builder().from_reader(input.as_bytes()));
+            assert!(
+                matches!(result, Err(EngineError::Parsing(_))),
+                "{ambiguous} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn state_hash_is_deterministic() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            deposit,2,2,3\n\
+            withdrawal,1,3,1.5\n\
+            dispute,2,2,\n\
+            dispute,1,1,\n\
+            resolve,1,1,\n";
+
+        let hash = |input: &str| {
+            let mut engine = super::Engine::new();
+            process_csv(&mut engine, input);
+            engine.state_hash()
+        };
+
+        assert_eq!(hash(input), hash(input));
+        assert_ne!(hash(input), hash(&input.replace("1.5", "1.6")));
+        // Empty engine hashes `{}`
+        assert_eq!(super::Engine::new().state_hash(), 0x08f4_4b07_b590_1a25);
+    }
+
+    #[test]
+    fn withdrawal_dispute_depends_on_storing_withdrawals() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            withdrawal,1,2,2\n\
+            dispute,1,2,\n";
 
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        process_csv(&mut engine, input);
+        assert!(engine.accounts[&1].under_dispute.contains(&2));
+        assert_eq!(engine.accounts[&1].held, dec!(2));
 
-        assert_eq!(
-            &AccountData {
-                available: dec!(1),
-                held: dec!(0),
-                locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
-        );
+        let mut engine = super::Engine::with_config(EngineConfig {
+            store_withdrawals_for_dispute: false,
+            ..Default::default()
+        });
+        process_csv(&mut engine, input);
+        assert!(engine.accounts[&1].under_dispute.is_empty());
+        assert_eq!(engine.accounts[&1].held, dec!(0));
+        assert_eq!(engine.error_counts.get("MissingTransaction"), Some(&1));
     }
 
     #[test]
-    fn deposit_dispute_and_chargeback_deposit() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Chargeback,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn withdrawal_disputes_config_key_still_accepted() {
+        let config: EngineConfig = toml::from_str("withdrawal_disputes = false").unwrap();
+        assert!(!config.store_withdrawals_for_dispute);
+    }
 
-        let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+    #[test]
+    fn error_log_lists_bad_rows() {
+        let input = "type,client,tx,amount
+            deposit,1,1,5
+            withdrawal,1,2,9
+            dispute,1,7,
+            deposit,1,3,1";
 
+        let log = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            quiet: true,
+            ..Default::default()
+        });
+        engine
+            .set_error_log(Box::new(log.clone()), ErrorLogFormat::Csv)
+            .unwrap();
+        process_csv(&mut engine, input);
         assert_eq!(
-            &AccountData {
-                available: dec!(0),
-                held: dec!(0),
-                locked: true,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            log.contents(),
+            "line,error_type,tx,client,message\n\
+            3,InsufficientFounds,2,1,insufficient founds for transaction `2`; account: `1`\n\
+            4,MissingTransaction,7,1,Referenced transaction `7` doesn't exist\n"
         );
+
+        let log = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        engine
+            .set_error_log(Box::new(log.clone()), ErrorLogFormat::Ndjson)
+            .unwrap();
+        process_csv(&mut engine, input);
+        let lines: Vec<serde_json::Value> = log
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["line"], 3);
+        assert_eq!(lines[0]["error_type"], "InsufficientFounds");
+        assert_eq!(lines[1]["line"], 4);
+        assert_eq!(lines[1]["tx"], 7);
     }
 
     #[test]
-    fn deposit_withdrawal_and_dispute_withdrawal() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn settled_ids_reuse() {
+        let reuse = || {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                reuse_settled_ids: true,
+                quiet: true,
+                ..Default::default()
+            });
+            engine.set_error_sink(Box::new(std::io::sink()));
+            engine
+        };
 
+        // Blocked without the option, for ids never disputed and for open disputes
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            dispute,1,1,\n\
+            resolve,1,1,\n\
+            deposit,1,1,3\n",
+        );
+        assert_eq!(engine.error_counts.get("DuplicatedTransaction"), Some(&1));
+        assert_eq!(engine.accounts[&1].available, dec!(5));
 
-        assert_eq!(
-            &AccountData {
-                available: dec!(1),
-                held: dec!(1),
-                locked: false,
-                under_dispute: HashSet::from_iter(vec![2]),
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+        let mut engine = reuse();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            deposit,1,1,3\n\
+            deposit,1,2,4\n\
+            dispute,1,2,\n\
+            withdrawal,1,2,1\n",
+        );
+        assert_eq!(engine.error_counts.get("DuplicatedTransaction"), Some(&2));
+        assert_eq!(engine.accounts[&1].available, dec!(5));
+
+        // Allowed once resolved, the new transaction replaces the old one
+        let mut engine = reuse();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            withdrawal,1,2,2\n\
+            dispute,1,2,\n\
+            resolve,1,2,\n\
+            deposit,1,2,3\n\
+            dispute,1,2,\n",
+        );
+        assert!(engine.error_counts.is_empty());
+        let account = &engine.accounts[&1];
+        assert_eq!(account.available, dec!(5));
+        assert_eq!(account.held, dec!(3));
+        assert_eq!(account.transactions[&2].operation, OperationType::Deposit);
+        assert_eq!(account.transactions.keys().last(), Some(&2));
+        assert_eq!(account.dispute_counts[&2], 1);
+
+        // Allowed after a chargeback, once the account is unlocked
+        let mut engine = reuse();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,1,1,3\n",
+        );
+        assert_eq!(engine.error_counts.get("AccountLocked"), Some(&1));
+        assert!(engine.unlock_account(1));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,3\n\
+            dispute,1,1,\n",
         );
+        assert_eq!(engine.accounts[&1].held, dec!(3));
+        assert_eq!(engine.accounts[&1].available, dec!(0));
     }
 
     #[test]
-    fn deposit_withdrawal_dispute_and_chargeback_withdrawal() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Chargeback,
-                client_id: 10,
-                amount: None,
-            },
-        ];
-
-        let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+    fn in_memory_account_store() {
+        let mut store = super::AccountsMap::<rust_decimal::Decimal>::new();
+        assert!(AccountStore::is_empty(&store));
 
-        assert_eq!(
-            &AccountData {
-                available: dec!(1),
-                held: dec!(0),
+        AccountStore::get_or_default(&mut store, 2).available = dec!(1);
+        AccountStore::get_or_default(&mut store, 2).held = dec!(2);
+        AccountStore::insert(
+            &mut store,
+            1,
+            AccountData {
                 locked: true,
                 ..Default::default()
             },
-            engine.accounts.get(&10).unwrap()
         );
+
+        assert_eq!(AccountStore::len(&store), 2);
+        assert_eq!(AccountStore::get(&store, 2).unwrap().held, dec!(2));
+        assert!(AccountStore::get_mut(&mut store, 3).is_none());
+        // Insertion order
+        let ids: Vec<ClientId> = AccountStore::iter(&store).map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![2, 1]);
     }
 
     #[test]
-    fn deposit_withdrawal_dispute_and_chargeback_deposit() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Chargeback,
-                client_id: 10,
-                amount: None,
-            },
-        ];
+    fn engine_with_custom_account_store() {
+        // Sorted by client id, counting created accounts. Stores don't need `Default`.
+        struct MockStore {
+            accounts: std::collections::BTreeMap<ClientId, AccountData>,
+            created: usize,
+        }
+
+        impl AccountStore<rust_decimal::Decimal> for MockStore {
+            fn get(&self, client_id: ClientId) -> Option<&AccountData> {
+                self.accounts.get(&client_id)
+            }
+
+            fn get_mut(&mut self, client_id: ClientId) -> Option<&mut AccountData> {
+                self.accounts.get_mut(&client_id)
+            }
+
+            fn get_or_default(&mut self, client_id: ClientId) -> &mut AccountData {
+                self.accounts.entry(client_id).or_insert_with(|| {
+                    self.created += 1;
+                    AccountData::default()
+                })
+            }
+
+            fn insert(&mut self, client_id: ClientId, account: AccountData) {
+                self.accounts.insert(client_id, account);
+            }
+
+            fn len(&self) -> usize {
+                self.accounts.len()
+            }
+
+            fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &AccountData)> + '_> {
+                Box::new(self.accounts.iter().map(|(id, account)| (*id, account)))
+            }
+
+            fn into_accounts(self) -> Box<dyn Iterator<Item = (ClientId, AccountData)>> {
+                Box::new(self.accounts.into_iter())
+            }
+        }
+
+        let input = "type,client,tx,amount\n\
+            deposit,3,1,5\n\
+            deposit,1,2,3\n\
+            withdrawal,3,3,1\n\
+            dispute,1,2,\n\
+            deposit,2,4,2\n\
+            dispute,2,4,\n\
+            chargeback,2,4,\n";
 
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        process_csv(&mut engine, input);
+        let mut expected = engine.report_rows().unwrap();
+        expected.sort_by_key(|row| row.client_id);
 
-        assert_eq!(
-            &AccountData {
-                available: dec!(-1),
-                held: dec!(0),
-                locked: true,
-                ..Default::default()
+        let mut mocked = super::Engine::with_store(
+            EngineConfig::default(),
+            MockStore {
+                accounts: Default::default(),
+                created: 0,
             },
-            engine.accounts.get(&10).unwrap()
         );
+        mocked
+            .process_from_reader(super::reader_builder().from_reader(input.as_bytes()))
+            .unwrap();
+
+        assert_eq!(mocked.report_rows().unwrap(), expected);
+        assert_eq!(mocked.accounts.created, 3);
+        assert_eq!(mocked.locked_clients(), vec![2]);
     }
 
     #[test]
-    fn deposit_withdrawal_dispute_and_resolve_deposit() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Resolve,
-                client_id: 10,
-                amount: None,
-            },
-        ];
-
+    fn withdrawal_disputes_capped_by_withdrawn_total() {
         let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,3\n\
+            withdrawal,1,3,4\n",
+        );
+        // State that input alone can't produce, e.g. from an inconsistent merge
+        engine.accounts.get_mut(&1).unwrap().total_withdrawn = dec!(5);
 
+        let dispute = |id| transaction(id, OperationType::Dispute, 1, None);
+        engine.process_one(dispute(2)).unwrap();
         assert_eq!(
-            &AccountData {
-                available: dec!(1),
-                held: dec!(0),
-                locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            engine.process_one(dispute(3)),
+            Err(ProcessingError::WithdrawalDisputeExceedsWithdrawn(3))
         );
+
+        let account = &engine.accounts[&1];
+        assert_eq!(account.held, dec!(3));
+        assert_eq!(account.under_dispute, HashSet::from([2]));
+
+        assert_eq!(account.withdrawal_disputes_held, dec!(3));
+
+        // Closed disputes don't count anymore
+        engine
+            .process_one(transaction(2, OperationType::Resolve, 1, None))
+            .unwrap();
+        assert_eq!(engine.accounts[&1].withdrawal_disputes_held, dec!(0));
+        engine.process_one(dispute(3)).unwrap();
+        assert_eq!(engine.accounts[&1].held, dec!(4));
+        assert_eq!(engine.accounts[&1].withdrawal_disputes_held, dec!(4));
     }
 
     #[test]
-    fn deposit_withdrawal_dispute_and_resolve_withdrawal() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(1)),
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Resolve,
-                client_id: 10,
-                amount: None,
-            },
-        ];
-
-        let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| engine.process_one(t).unwrap());
+    fn trace_has_result_of_every_record() {
+        let trace = SharedBuffer::default();
+        let mut engine = super::Engine::with_config(EngineConfig {
+            skip_bad_rows: true,
+            exclude_clients: Some(HashSet::from([3])),
+            quiet: true,
+            ..Default::default()
+        });
+        engine.set_trace(Box::new(trace.clone()));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            withdrawal,1,2,9\n\
+            deposit,3,3,1\n\
+            transfer,1,4,1\n\
+            dispute,1,1,\n\
+            dispute,1,1,\n",
+        );
 
         assert_eq!(
-            &AccountData {
-                available: dec!(2),
-                held: dec!(0),
-                locked: false,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            trace.contents(),
+            "1,2,applied\n\
+            2,3,skipped:InsufficientFounds\n\
+            3,4,filtered\n\
+            4,5,skipped:Parsing\n\
+            5,6,applied\n\
+            6,7,skipped:DuplicatedDispute\n"
         );
+
+        // Without line numbers
+        let trace = SharedBuffer::default();
+        let mut engine = super::Engine::new();
+        engine.set_error_sink(Box::new(std::io::sink()));
+        engine.set_trace(Box::new(trace.clone()));
+        let withdrawal = transaction(1, OperationType::Withdrawal, 1, Some(dec!(1)));
+        engine.process_iter([withdrawal]).unwrap();
+        assert_eq!(trace.contents(), "1,,skipped:InsufficientFounds\n");
     }
 
     #[test]
-    fn no_deposit_on_locked_account() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Chargeback,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-        ];
+    fn deposit_over_balance_cap_rejected() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            max_account_balance: Some(dec!(10)),
+            ..Default::default()
+        });
+        engine.set_error_sink(Box::new(std::io::sink()));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,4\n\
+            withdrawal,1,2,1\n\
+            deposit,1,3,3\n\
+            dispute,1,3,\n\
+            deposit,1,4,4.0001\n",
+        );
+        let account = &engine.accounts[&1];
+        assert_eq!((account.available, account.held), (dec!(3), dec!(3)));
+        assert_eq!(engine.error_counts.get("BalanceCapExceeded"), Some(&1));
+        assert_eq!(account.lifetime_totals().deposited, dec!(7));
 
-        let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        // Exactly at the cap is fine
+        let deposit = transaction(5, OperationType::Deposit, 1, Some(dec!(4)));
+        engine.process_one(deposit).unwrap();
+        assert_eq!(engine.accounts[&1].available, dec!(7));
+    }
 
-        assert_eq!(
-            &AccountData {
-                available: dec!(0),
-                held: dec!(0),
-                locked: true,
+    #[test]
+    fn report_dust_handling() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,0.00003\n\
+            deposit,1,2,0.00003\n\
+            deposit,1,3,0.00006\n\
+            dispute,1,3,\n\
+            deposit,2,4,1.23456\n";
+        let report = |report_dust| {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                report_dust,
                 ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            });
+            process_csv(&mut engine, input);
+            let mut report = Vec::new();
+            engine.serialize_report_to_writer(&mut report).unwrap();
+            String::from_utf8(report).unwrap()
+        };
+
+        // Rounded separately, 0.0001 + 0.0001 isn't 0.0001
+        assert_eq!(
+            report(DustHandling::Round),
+            "client,available,held,total,locked\n\
+            1,0.0001,0.0001,0.0001,false\n\
+            2,1.2346,0,1.2346,false\n"
+        );
+        assert_eq!(
+            report(DustHandling::Drop),
+            "client,available,held,total,locked\n\
+            1,0,0,0,false\n\
+            2,1.2345,0,1.2345,false\n"
+        );
+        // Total plus dust is the stored total
+        assert_eq!(
+            report(DustHandling::Sweep),
+            "client,available,held,total,locked,dust\n\
+            1,0,0,0,false,0.00012\n\
+            2,1.2345,0,1.2345,false,0.00006\n"
         );
     }
 
     #[test]
-    fn no_withdrawal_on_locked_account() {
-        let transactions: Vec<Transaction> = vec![
-            Transaction {
-                id: 1,
-                operation: OperationType::Deposit,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Dispute,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 1,
-                operation: OperationType::Chargeback,
-                client_id: 10,
-                amount: None,
-            },
-            Transaction {
-                id: 2,
-                operation: OperationType::Withdrawal,
-                client_id: 10,
-                amount: Some(dec!(2)),
-            },
-        ];
+    fn report_dust_totals_add_up_rows() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,0.00006\n\
+            deposit,1,2,0.00006\n\
+            dispute,1,2,\n\
+            deposit,2,3,1.23456\n";
+        let report = |report_dust| {
+            let mut engine = super::Engine::with_config(EngineConfig {
+                report_dust,
+                report_totals: true,
+                ..Default::default()
+            });
+            process_csv(&mut engine, input);
+            let mut report = Vec::new();
+            engine.serialize_report_to_writer(&mut report).unwrap();
+            String::from_utf8(report).unwrap()
+        };
 
-        let mut engine = super::Engine::new();
-        transactions
-            .into_iter()
-            .for_each(|t| _ = engine.process_one(t));
+        // Truncating the stored sums would show 1.2346
+        assert_eq!(
+            report(DustHandling::Drop),
+            "client,available,held,total,locked\n\
+            1,0,0,0,false\n\
+            2,1.2345,0,1.2345,false\n\
+            ,1.2345,0,1.2345,\n"
+        );
+        assert_eq!(
+            report(DustHandling::Sweep),
+            "client,available,held,total,locked,dust\n\
+            1,0,0,0,false,0.00012\n\
+            2,1.2345,0,1.2345,false,0.00006\n\
+            ,1.2345,0,1.2345,,0.00018\n"
+        );
+    }
+
+    #[test]
+    fn report_serialization_from_shared_reference() {
+        let mut engine = super::Engine::with_config(EngineConfig {
+            report_totals: true,
+            ..Default::default()
+        });
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+            deposit,1,1,2\n\
+            deposit,2,2,3\n\
+            dispute,2,2,\n",
+        );
 
+        let shared = &engine;
+        let hash = shared.state_hash();
+        let serialize = || {
+            let mut report = Vec::new();
+            shared.serialize_report_to_writer(&mut report).unwrap();
+            report
+        };
+        let first = serialize();
         assert_eq!(
-            &AccountData {
-                available: dec!(0),
-                held: dec!(0),
-                locked: true,
-                ..Default::default()
-            },
-            engine.accounts.get(&10).unwrap()
+            String::from_utf8(first.clone()).unwrap(),
+            "client,available,held,total,locked\n\
+            1,2,0,2,false\n\
+            2,0,3,3,false\n\
+            ,2,3,5,\n"
         );
+        assert_eq!(serialize(), first);
+        // Writing to stdout only needs a shared reference too
+        let _: fn(&super::Engine) -> Result<(), EngineError> =
+            super::Engine::serialize_report_stdout;
+        assert_eq!(shared.state_hash(), hash);
+        assert_eq!(shared.stats().read, 3);
     }
 }
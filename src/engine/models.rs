@@ -1,16 +1,31 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
 
+use chrono::{DateTime, Utc};
 use indexmap::IndexMap;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use strum::Display;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use strum::{Display, EnumIter, IntoEnumIterator, IntoStaticStr};
 
+use super::amount::Amount;
+use super::error::LocatedError;
+
+// Compact by default, `wide-ids` is for deployments with more than 65535 clients
+#[cfg(not(feature = "wide-ids"))]
 pub type ClientId = u16;
+#[cfg(not(feature = "wide-ids"))]
 pub type TransactionId = u32;
 
-pub type AccountsMap = IndexMap<ClientId, AccountData>;
+#[cfg(feature = "wide-ids")]
+pub type ClientId = u32;
+#[cfg(feature = "wide-ids")]
+pub type TransactionId = u64;
+
+pub type AccountsMap<A = Decimal> = IndexMap<ClientId, AccountData<A>>;
 
-#[derive(Debug, Deserialize, Clone, Display, PartialEq, Eq)]
+#[derive(Debug, Serialize, Clone, Display, EnumIter, IntoStaticStr, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationType {
     Deposit,
@@ -18,54 +33,300 @@ pub enum OperationType {
     Dispute,
     Resolve,
     Chargeback,
+    // Debugging marker, logs the balance of a client without changing it
+    Query,
+    // Creates the account, so it's reported even without any funds moving
+    Open,
+}
+
+impl OperationType {
+    // One line summary of what a row of this type does
+    pub fn description(&self) -> &'static str {
+        match self {
+            OperationType::Deposit => "credits the amount to the available funds of the client",
+            OperationType::Withdrawal => {
+                "debits the amount from the available funds, if there are enough of them"
+            }
+            OperationType::Dispute => {
+                "moves the amount of the referenced transaction from available to held"
+            }
+            OperationType::Resolve => {
+                "releases held funds of a disputed transaction back to available"
+            }
+            OperationType::Chargeback => {
+                "removes held funds of a disputed transaction and locks the account"
+            }
+            OperationType::Query => "logs the balance of the client without changing it",
+            OperationType::Open => "creates the account with zero balances, if it doesn't exist",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct Transaction {
+// Case-insensitive and tolerant to surrounding whitespace, e.g. ` DEPOSIT `
+impl<'de> Deserialize<'de> for OperationType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let name = value.trim();
+
+        OperationType::iter()
+            .find(|operation| <&str>::from(operation).eq_ignore_ascii_case(name))
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown operation type `{name}`")))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(bound(deserialize = "A: Amount"), try_from = "TransactionRow<A>")]
+pub struct Transaction<A = Decimal> {
     #[serde(rename = "type")]
     pub operation: OperationType,
     #[serde(rename = "client")]
     pub client_id: ClientId,
+    // 0 for rows that don't reference a transaction, e.g. `query, 1`
     #[serde(rename = "tx")]
     pub id: TransactionId,
 
     // None if not provided at all
     #[serde(default)]
-    pub amount: Option<Decimal>,
+    pub amount: Option<A>,
+
+    // None if the column is absent or empty
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+
+    // ISO currency code, None if the column is absent or empty
+    #[serde(default)]
+    pub currency: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct AccountData {
+// Parsed from text, so out of range values (e.g. 29 digits) are reported with the
+// offending value instead of a generic type mismatch. Trailing zeros are kept, the
+// engine strips them unless `normalize_amounts` is off.
+fn parse_amount<'de, D: Deserializer<'de>, A: Amount>(
+    deserializer: D,
+) -> Result<Option<A>, D::Error> {
+    let Some(raw) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    let raw = raw.trim();
+    let value = Decimal::from_str(raw)
+        .or_else(|_| Decimal::from_scientific(raw))
+        .map_err(|e| serde::de::Error::custom(format!("invalid amount `{raw}`: {e}")))?;
+    // With all 29 digits in use sums would be rounded by `Decimal` instead of overflowing
+    if value.mantissa().unsigned_abs() >= 10_u128.pow(28) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid amount `{raw}`: more than 28 digits"
+        )));
+    }
+    A::from_decimal(value)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("amount `{raw}` can't be represented")))
+}
+
+// Comma locale amount with a `.` that doesn't separate thousands, e.g. `0.5`.
+// Deserializing it always fails, so the row is reported like any other bad amount.
+#[derive(Deserialize)]
+pub(crate) struct MisplacedDot {
+    #[serde(deserialize_with = "reject_misplaced_dot")]
+    pub amount: Infallible,
+}
+
+fn reject_misplaced_dot<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Infallible, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    Err(serde::de::Error::custom(format!(
+        "invalid amount `{}`: `.` only separates thousands in the comma decimal locale",
+        raw.trim()
+    )))
+}
+
+// Row as read. Only rows that don't reference a transaction can leave out `tx`.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "A: Amount"))]
+struct TransactionRow<A> {
+    #[serde(rename = "type")]
+    operation: OperationType,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    // Short rows can't use `#[serde(default)]`, csv only tolerates missing `Option` fields
+    #[serde(rename = "tx")]
+    id: Option<TransactionId>,
+    #[serde(default, deserialize_with = "parse_amount")]
+    amount: Option<A>,
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+impl<A> TryFrom<TransactionRow<A>> for Transaction<A> {
+    type Error = String;
+
+    fn try_from(row: TransactionRow<A>) -> Result<Self, Self::Error> {
+        let id = match (row.id, &row.operation) {
+            (Some(id), _) => id,
+            (None, OperationType::Query | OperationType::Open) => 0,
+            (None, operation) => return Err(format!("missing `tx` of a `{operation}` row")),
+        };
+
+        Ok(Transaction {
+            operation: row.operation,
+            client_id: row.client_id,
+            id,
+            amount: row.amount,
+            timestamp: row.timestamp,
+            currency: row.currency,
+        })
+    }
+}
+
+// Serialized form is deterministic: transactions keep application order
+// and id sets are sorted
+#[derive(Debug, Serialize)]
+pub struct AccountData<A = Decimal> {
     pub locked: bool,
-    pub available: Decimal,
-    pub held: Decimal,
+    pub available: A,
+    pub held: A,
 
-    pub transactions: HashMap<TransactionId, Transaction>,
+    // Insertion ordered, so history can be returned in application order
+    pub transactions: IndexMap<TransactionId, Transaction<A>>,
+    #[serde(serialize_with = "serialize_sorted")]
     pub under_dispute: HashSet<TransactionId>,
+    // Ids of withdrawals that were not stored in `transactions`
+    #[serde(serialize_with = "serialize_sorted")]
+    pub withdrawal_ids: HashSet<TransactionId>,
+    // Ids of deposits that were not stored because a withdrawal already uses the id,
+    // only with `dedupe_per_operation`
+    #[serde(serialize_with = "serialize_sorted")]
+    pub deposit_ids: HashSet<TransactionId>,
+    // Established by the first applied transaction carrying a currency
+    pub currency: Option<String>,
+    // Held funds loaded from a previous report, not backed by any known dispute
+    pub opening_held: A,
+    // Amounts actually held for disputes that could only be partially covered
+    pub partial_holds: BTreeMap<TransactionId, A>,
+    // Held funds of open withdrawal disputes, capped by `total_withdrawn`
+    pub withdrawal_disputes_held: A,
+    // Number of times each transaction was disputed, resolved disputes included, so its
+    // keys are every transaction that was ever disputed
+    pub dispute_counts: BTreeMap<TransactionId, u32>,
+    // Dispute lifecycle in application order, only kept with `record_events`
+    pub events: Vec<AccountEvent>,
+    // Lifetime sums of applied deposits and withdrawals, disputes don't change them
+    pub total_deposited: A,
+    pub total_withdrawn: A,
+    // Lifetime sums of disputed withdrawals and of charged back amounts, the two ways
+    // a dispute changes the total
+    pub total_disputed_withdrawals: A,
+    pub total_charged_back: A,
+}
+
+fn serialize_sorted<S: Serializer>(
+    ids: &HashSet<TransactionId>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut sorted: Vec<_> = ids.iter().collect();
+    sorted.sort_unstable();
+    serializer.collect_seq(sorted)
+}
+
+// Ids are those of the disputed transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AccountEvent {
+    DisputeOpened(TransactionId),
+    Resolved(TransactionId),
+    ChargedBack(TransactionId),
 }
 
-impl PartialEq for AccountData {
+impl<A: Amount> AccountData<A> {
+    pub fn lifetime_totals(&self) -> LifetimeTotals {
+        LifetimeTotals {
+            deposited: self.total_deposited.to_decimal(),
+            withdrawn: self.total_withdrawn.to_decimal(),
+        }
+    }
+
+    // Stored deposits and withdrawals split by whether they were ever disputed
+    pub fn reference_counts(&self) -> ReferenceCounts {
+        let referenced = self
+            .transactions
+            .keys()
+            .filter(|id| self.dispute_counts.contains_key(id))
+            .count();
+        ReferenceCounts {
+            referenced,
+            unreferenced: self.transactions.len() - referenced,
+        }
+    }
+
+    pub fn has_transaction(&self, id: TransactionId) -> bool {
+        self.transactions.contains_key(&id)
+            || self.withdrawal_ids.contains(&id)
+            || self.deposit_ids.contains(&id)
+    }
+
+    // Stored transaction that was disputed at least once and has no open dispute. Ids
+    // also kept in `withdrawal_ids` or `deposit_ids` belong to another transaction too
+    // and are never settled.
+    pub fn is_settled(&self, id: TransactionId) -> bool {
+        self.transactions.contains_key(&id)
+            && self.dispute_counts.contains_key(&id)
+            && !self.under_dispute.contains(&id)
+            && !self.withdrawal_ids.contains(&id)
+            && !self.deposit_ids.contains(&id)
+    }
+
+    // Whether a deposit or a withdrawal with this id was applied
+    pub fn has_operation(&self, operation: &OperationType, id: TransactionId) -> bool {
+        let unstored = match operation {
+            OperationType::Deposit => &self.deposit_ids,
+            OperationType::Withdrawal => &self.withdrawal_ids,
+            _ => return false,
+        };
+        unstored.contains(&id)
+            || self
+                .transactions
+                .get(&id)
+                .is_some_and(|stored| stored.operation == *operation)
+    }
+}
+
+impl<A: Amount> PartialEq for AccountData<A> {
     fn eq(&self, other: &Self) -> bool {
         (self.locked == other.locked)
             && (self.available == other.available)
             && (self.held == other.held)
             && (self.under_dispute == other.under_dispute)
+            && (self.currency == other.currency)
     }
 }
 
-impl Default for AccountData {
+impl<A: Amount> Default for AccountData<A> {
     fn default() -> Self {
         Self {
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
+            available: A::ZERO,
+            held: A::ZERO,
             locked: false,
             under_dispute: HashSet::new(),
-            transactions: HashMap::new(),
+            transactions: IndexMap::new(),
+            withdrawal_ids: HashSet::new(),
+            deposit_ids: HashSet::new(),
+            currency: None,
+            opening_held: A::ZERO,
+            partial_holds: BTreeMap::new(),
+            withdrawal_disputes_held: A::ZERO,
+            dispute_counts: BTreeMap::new(),
+            events: Vec::new(),
+            total_deposited: A::ZERO,
+            total_withdrawn: A::ZERO,
+            total_disputed_withdrawals: A::ZERO,
+            total_charged_back: A::ZERO,
         }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ReportRow {
     #[serde(rename = "client")]
     pub client_id: ClientId,
@@ -73,4 +334,94 @@ pub struct ReportRow {
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    // Number of open disputes, only in detailed reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disputed: Option<usize>,
+    // Stored total minus the reported one, only when sweeping dust
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dust: Option<Decimal>,
+}
+
+// Same layout as a csv report line
+impl fmt::Display for ReportRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.client_id, self.available, self.held, self.total, self.locked
+        )?;
+        if let Some(disputed) = self.disputed {
+            write!(f, ",{disputed}")?;
+        }
+        if let Some(dust) = self.dust {
+            write!(f, ",{dust}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ReportRow {
+    // Written explicitly when there are no rows, so the output is still a valid report
+    pub const HEADER: [&'static str; 5] = ["client", "available", "held", "total", "locked"];
+    pub const DETAILED_HEADER: [&'static str; 6] =
+        ["client", "available", "held", "total", "locked", "disputed"];
+}
+
+// Lifetime sums of applied deposits and withdrawals of an account
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LifetimeTotals {
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+}
+
+impl LifetimeTotals {
+    // Deposits minus withdrawals, what the account would hold without any disputes
+    pub fn net_position(&self) -> Decimal {
+        self.deposited.saturating_sub(self.withdrawn)
+    }
+}
+
+// Stored transactions of an account that were disputed at least once (and possibly
+// resolved or charged back since) and those that never were
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReferenceCounts {
+    pub referenced: usize,
+    pub unreferenced: usize,
+}
+
+// Sums over all accounts, optionally written as a report footer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReportTotals {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    // Summed dust of the rows, only with `DustHandling::Sweep`
+    pub dust: Option<Decimal>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessingStats {
+    pub read: usize,
+    pub applied: usize,
+    pub skipped: usize,
+    // Rows of clients excluded by the client filter
+    pub filtered: usize,
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records read, {} applied, {} skipped, {} filtered",
+            self.read, self.applied, self.skipped, self.filtered
+        )
+    }
+}
+
+// Everything `Engine::run` produces, without writing anything out
+#[derive(Debug)]
+pub struct ProcessOutcome {
+    pub rows: Vec<ReportRow>,
+    pub errors: Vec<LocatedError>,
+    pub stats: ProcessingStats,
 }
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use csv::{Reader, StringRecord, Writer};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::error::EngineError;
+use super::models::{ClientId, ReportRow, ReportTotals};
+
+// Destination of the final report. The engine emits one row per account, then the
+// totals (only if enabled in the config) and finally calls `finish`.
+pub trait ReportSink {
+    fn emit(&mut self, row: &ReportRow) -> Result<(), EngineError>;
+
+    // Sinks without a place for a summary can ignore it
+    fn emit_totals(&mut self, _totals: &ReportTotals) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError>;
+}
+
+// The original report format
+pub struct CsvReportSink<T: Write> {
+    writer: Writer<T>,
+    header_written: bool,
+    rows: usize,
+    flush_interval: Option<usize>,
+    detailed: bool,
+    dust: bool,
+}
+
+impl<T: Write> CsvReportSink<T> {
+    pub fn new(writer: Writer<T>) -> Self {
+        Self {
+            writer,
+            header_written: false,
+            rows: 0,
+            flush_interval: None,
+            detailed: false,
+            dust: false,
+        }
+    }
+
+    // Rows carry the `disputed` column, the header and the footer need it too
+    pub fn with_detailed(mut self, detailed: bool) -> Self {
+        self.detailed = detailed;
+        self
+    }
+
+    // Rows carry the `dust` column (after `disputed`), the header and the footer too
+    pub fn with_dust(mut self, dust: bool) -> Self {
+        self.dust = dust;
+        self
+    }
+
+    // Flush every that many rows, so a consumer can start reading before the end
+    pub fn with_flush_interval(mut self, flush_interval: Option<usize>) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+}
+
+impl<T: Write> ReportSink for CsvReportSink<T> {
+    fn emit(&mut self, row: &ReportRow) -> Result<(), EngineError> {
+        // Header comes with the first serialized row
+        self.writer.serialize(row)?;
+        self.header_written = true;
+
+        self.rows += 1;
+        if self
+            .flush_interval
+            .is_some_and(|interval| self.rows.is_multiple_of(interval))
+        {
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Client column is left empty, so the footer can't be mistaken for an account
+    fn emit_totals(&mut self, totals: &ReportTotals) -> Result<(), EngineError> {
+        self.write_header_if_empty()?;
+        let mut footer = vec![
+            String::new(),
+            totals.available.to_string(),
+            totals.held.to_string(),
+            totals.total.to_string(),
+            String::new(),
+        ];
+        if self.detailed {
+            footer.push(String::new());
+        }
+        if self.dust {
+            footer.push(totals.dust.map(|dust| dust.to_string()).unwrap_or_default());
+        }
+        self.writer.write_record(footer)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        self.write_header_if_empty()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: Write> CsvReportSink<T> {
+    // Without rows the header is written explicitly, so the output is still a valid report
+    fn write_header_if_empty(&mut self) -> Result<(), EngineError> {
+        if !self.header_written {
+            let mut header = match self.detailed {
+                true => ReportRow::DETAILED_HEADER.to_vec(),
+                false => ReportRow::HEADER.to_vec(),
+            };
+            if self.dust {
+                header.push("dust");
+            }
+            self.writer.write_record(header)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+// Wire form of a binary report row. Amounts are kept in the 16 byte `Decimal`
+// representation, so they are exact and keep their scale. The detailed `disputed`
+// and the `dust` columns are not part of it.
+#[derive(Serialize, Deserialize)]
+struct BinaryReportRow {
+    client: ClientId,
+    available: [u8; 16],
+    held: [u8; 16],
+    total: [u8; 16],
+    locked: bool,
+}
+
+impl From<&ReportRow> for BinaryReportRow {
+    fn from(row: &ReportRow) -> Self {
+        Self {
+            client: row.client_id,
+            available: row.available.serialize(),
+            held: row.held.serialize(),
+            total: row.total.serialize(),
+            locked: row.locked,
+        }
+    }
+}
+
+impl From<BinaryReportRow> for ReportRow {
+    fn from(row: BinaryReportRow) -> Self {
+        Self {
+            client_id: row.client,
+            available: Decimal::deserialize(row.available),
+            held: Decimal::deserialize(row.held),
+            total: Decimal::deserialize(row.total),
+            locked: row.locked,
+            disputed: None,
+            dust: None,
+        }
+    }
+}
+
+// Every row is a bincode record preceded by its length as a little endian `u32`.
+// There is no header and no totals footer, those can be recomputed from the rows.
+pub struct BinaryReportSink<T: Write> {
+    writer: T,
+}
+
+impl<T: Write> BinaryReportSink<T> {
+    pub fn new(writer: T) -> Self {
+        Self { writer }
+    }
+}
+
+impl<T: Write> ReportSink for BinaryReportSink<T> {
+    fn emit(&mut self, row: &ReportRow) -> Result<(), EngineError> {
+        let record = bincode::serialize(&BinaryReportRow::from(row))?;
+        self.writer
+            .write_all(&(record.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Keeps the rows in memory, e.g. for `Engine::run`
+impl ReportSink for Vec<ReportRow> {
+    fn emit(&mut self, row: &ReportRow) -> Result<(), EngineError> {
+        self.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+}
+
+// Rows of a previously written report, a totals footer is skipped
+pub fn read_report<T: Read>(mut reader: Reader<T>) -> Result<Vec<ReportRow>, EngineError> {
+    let headers: StringRecord = reader.headers()?.iter().map(str::to_lowercase).collect();
+
+    let mut rows = Vec::new();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        // Totals footer has an empty client column
+        if record.get(0).is_some_and(str::is_empty) {
+            continue;
+        }
+        rows.push(record.deserialize(Some(&headers))?);
+    }
+
+    Ok(rows)
+}
+
+// A row is at most 53 bytes (with `wide-ids`), anything longer comes from a corrupt file
+const MAX_BINARY_RECORD_LEN: usize = 64;
+
+// Rows of a report written by `BinaryReportSink`
+pub fn read_binary_report<T: Read>(mut reader: T) -> Result<Vec<ReportRow>, EngineError> {
+    let mut rows = Vec::new();
+    let mut length = [0u8; 4];
+    loop {
+        // End of input is only expected between records, a cut off length is an error
+        if reader.read(&mut length[..1])? == 0 {
+            break;
+        }
+        reader.read_exact(&mut length[1..])?;
+
+        // The length isn't trusted with an allocation
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_BINARY_RECORD_LEN {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit).into());
+        }
+        let mut record = vec![0u8; length];
+        reader.read_exact(&mut record)?;
+        rows.push(bincode::deserialize::<BinaryReportRow>(&record)?.into());
+    }
+
+    Ok(rows)
+}
+
+// Differences between two reports in client order, `-` lines are only in the expected
+// report and `+` lines only in the actual one. Row order doesn't matter and amounts
+// are compared by value, so `2` equals `2.0`.
+pub fn diff_reports(expected: &[ReportRow], actual: &[ReportRow]) -> Vec<String> {
+    let (expected, actual) = (by_client(expected), by_client(actual));
+
+    let mut clients: Vec<ClientId> = expected.keys().chain(actual.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut differences = Vec::new();
+    for client_id in clients {
+        let (expected, actual) = (expected.get(&client_id), actual.get(&client_id));
+        if expected == actual {
+            continue;
+        }
+        if let Some(row) = expected {
+            differences.push(format!("- {row}"));
+        }
+        if let Some(row) = actual {
+            differences.push(format!("+ {row}"));
+        }
+    }
+    differences
+}
+
+// Changes from the `old` to the `new` report, one line per changed client (e.g.
+// `client 1: available +1.5, total +1.5, locked false -> true`) followed by the added
+// and removed clients. Amounts are compared by value, so `2` equals `2.0`.
+pub fn report_deltas(old: &[ReportRow], new: &[ReportRow]) -> Vec<String> {
+    let (old, new) = (by_client(old), by_client(new));
+
+    let mut lines = Vec::new();
+    for (client_id, after) in &new {
+        let Some(before) = old.get(client_id) else {
+            continue;
+        };
+
+        let mut changes = Vec::new();
+        for (column, before, after) in [
+            ("available", before.available, after.available),
+            ("held", before.held, after.held),
+            ("total", before.total, after.total),
+        ] {
+            match after.checked_sub(before) {
+                Some(delta) if delta.is_zero() => {}
+                Some(delta) => changes.push(format!("{column} {delta:+}")),
+                None => changes.push(format!("{column} {before} -> {after}")),
+            }
+        }
+        if before.locked != after.locked {
+            changes.push(format!("locked {} -> {}", before.locked, after.locked));
+        }
+
+        if !changes.is_empty() {
+            lines.push(format!("client {client_id}: {}", changes.join(", ")));
+        }
+    }
+
+    let missing_from = |rows: &BTreeMap<ClientId, ReportRow>, other: &BTreeMap<_, _>| {
+        rows.keys()
+            .filter(|client_id| !other.contains_key(*client_id))
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    };
+    let added = missing_from(&new, &old);
+    if !added.is_empty() {
+        lines.push(format!("added clients: {}", added.join(", ")));
+    }
+    let removed = missing_from(&old, &new);
+    if !removed.is_empty() {
+        lines.push(format!("removed clients: {}", removed.join(", ")));
+    }
+
+    lines
+}
+
+fn by_client(rows: &[ReportRow]) -> BTreeMap<ClientId, ReportRow> {
+    rows.iter()
+        .map(|row| (row.client_id, row.clone()))
+        .collect()
+}
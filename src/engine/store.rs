@@ -0,0 +1,56 @@
+use super::amount::Amount;
+use super::models::{AccountData, AccountsMap, ClientId};
+
+// Storage of account states used by the engine. `AccountsMap` keeps them in memory,
+// other implementations (e.g. disk backed, for client sets exceeding the memory) are
+// plugged in with `Engine::with_store`. Iteration order is the order of the report.
+pub trait AccountStore<A: Amount> {
+    fn get(&self, client_id: ClientId) -> Option<&AccountData<A>>;
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut AccountData<A>>;
+    // Creates the account with zero balances if it doesn't exist
+    fn get_or_default(&mut self, client_id: ClientId) -> &mut AccountData<A>;
+    // Replaces the account if it exists
+    fn insert(&mut self, client_id: ClientId, account: AccountData<A>);
+    fn len(&self) -> usize;
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &AccountData<A>)> + '_>;
+    // Consumes the store, e.g. when merging engines
+    fn into_accounts(self) -> Box<dyn Iterator<Item = (ClientId, AccountData<A>)>>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Accounts in the order they first appeared in the input
+impl<A: Amount> AccountStore<A> for AccountsMap<A> {
+    fn get(&self, client_id: ClientId) -> Option<&AccountData<A>> {
+        self.get(&client_id)
+    }
+
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut AccountData<A>> {
+        self.get_mut(&client_id)
+    }
+
+    fn get_or_default(&mut self, client_id: ClientId) -> &mut AccountData<A> {
+        self.entry(client_id).or_default()
+    }
+
+    fn insert(&mut self, client_id: ClientId, account: AccountData<A>) {
+        self.insert(client_id, account);
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &AccountData<A>)> + '_> {
+        Box::new(
+            self.iter()
+                .map(|(client_id, account)| (*client_id, account)),
+        )
+    }
+
+    fn into_accounts(self) -> Box<dyn Iterator<Item = (ClientId, AccountData<A>)>> {
+        Box::new(self.into_iter())
+    }
+}
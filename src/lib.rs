@@ -0,0 +1,30 @@
+pub mod engine;
+
+/// Types needed to process transactions and read the results.
+///
+/// ```
+/// use transponster::prelude::*;
+///
+/// let input = "type, client, tx, amount
+/// deposit, 1, 1, 2.0
+/// withdrawal, 1, 2, 0.5";
+///
+/// let mut engine = Engine::new();
+/// engine.process_stream(input.as_bytes())?;
+///
+/// let mut report = Vec::new();
+/// engine.serialize_report_to_writer(&mut report)?;
+/// assert_eq!(
+///     String::from_utf8(report).unwrap(),
+///     "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+/// );
+/// # Ok::<(), EngineError>(())
+/// ```
+pub mod prelude {
+    pub use crate::engine::config::EngineConfig;
+    pub use crate::engine::error::{EngineError, ProcessingError};
+    pub use crate::engine::models::{
+        AccountData, OperationType, ProcessOutcome, ReportRow, Transaction,
+    };
+    pub use crate::engine::Engine;
+}
@@ -1,26 +1,655 @@
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{bail, Result};
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-mod engine;
-use engine::Engine;
+use transponster::engine::config::{
+    DecimalLocale, DustHandling, ErrorLogFormat, InputFormat, LogFormat, QuoteStyle, ReportFormat,
+    SortColumn,
+};
+use transponster::engine::models::ClientId;
+use transponster::engine::reader_builder;
+use transponster::engine::report::{diff_reports, read_report, report_deltas};
+use transponster::prelude::*;
 
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
+use strum::IntoEnumIterator;
+
+// Records between two progress lines
+const PROGRESS_INTERVAL: usize = 100_000;
+
+// How often `--watch` checks the input file for appended records
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Known-good input of `--selftest` covering every operation type, failing rows and
+// amounts with the full precision
+const SELFTEST_INPUT: &str = "\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+deposit, 3, 6, 10.1234
+dispute, 3, 6,
+deposit, 4, 7, 0.0001
+withdrawal, 4, 8, 0.0001
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 1, 9, 1000000.9999
+dispute, 1, 9,
+resolve, 1, 9,
+query, 1
+open, 5
+";
+
+const SELFTEST_REPORT: &str = "\
+client,available,held,total,locked
+1,1000002.4999,0,1000002.4999,false
+2,0,0,0,true
+3,0,10.1234,10.1234,false
+4,0,0,0,false
+5,0,0,0,false
+";
+
+// Without a subcommand the arguments are those of `process`, as before subcommands existed
+#[derive(Debug, StructOpt)]
+#[structopt(settings = &[AppSettings::SubcommandsNegateReqs, AppSettings::ArgsNegateSubcommands])]
+struct Cli {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    #[structopt(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Process an input file and print the report (the default)
+    Process(Args),
+
+    /// Process an input file and compare the report with an existing one instead of
+    /// printing it. Differences are printed to stderr and the exit code is non-zero.
+    Verify {
+        #[structopt(parse(from_os_str))]
+        report: PathBuf,
+
+        #[structopt(flatten)]
+        args: Args,
+    },
+
+    /// Print per client changes between two report files
+    Diff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
+
+    /// List supported operation types with a short description
+    Explain,
+
+    /// Process a built-in input and compare the report with the expected one
+    Selftest,
+}
+
+impl Cli {
+    fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Process(self.args))
+    }
+}
 
 #[derive(Debug, StructOpt)]
 struct Args {
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    #[structopt(parse(from_os_str), required_unless_one = &["explain", "diff", "selftest"])]
+    input: Option<PathBuf>,
+
+    /// Deprecated, use the `diff` subcommand
+    #[structopt(
+        long,
+        hidden = true,
+        number_of_values = 2,
+        value_names = &["old", "new"],
+        parse(from_os_str)
+    )]
+    diff: Option<Vec<PathBuf>>,
+
+    /// Previously written report whose balances are used as the starting state
+    #[structopt(long, parse(from_os_str))]
+    merge_report: Option<PathBuf>,
+
+    /// Write every applied transaction to this file, replaying it reproduces the report
+    #[structopt(long, parse(from_os_str))]
+    replay_log: Option<PathBuf>,
+
+    /// Write the result of every record (`applied`, `filtered` or `skipped:<error>`) to
+    /// this file, one line per record
+    #[structopt(long, parse(from_os_str))]
+    trace: Option<PathBuf>,
+
+    /// Also write every error with its line number to this file, as csv or as ndjson
+    /// for `.ndjson`, `.jsonl` and `.json` files
+    #[structopt(long, parse(from_os_str))]
+    error_log: Option<PathBuf>,
+
+    /// Deprecated, use the `verify` subcommand. Also set by it.
+    #[structopt(long, hidden = true, parse(from_os_str))]
+    verify: Option<PathBuf>,
+
+    /// Keep processing records appended to the csv input until Ctrl-C, then write
+    /// the report
+    #[structopt(long)]
+    watch: bool,
+
+    /// Deprecated, use the `explain` subcommand
+    #[structopt(long, hidden = true)]
+    explain: bool,
+
+    /// Deprecated, use the `selftest` subcommand
+    #[structopt(long, hidden = true)]
+    selftest: bool,
+
+    /// Engine settings file (TOML, or JSON with `.json` extension). Options given on
+    /// the command line take precedence over the file.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Print processing summary and answers to query rows to stderr
+    #[structopt(short, long)]
+    verbose: bool,
+
+    /// Don't print processing errors, warnings, progress and the summary to stderr
+    #[structopt(short, long)]
+    quiet: bool,
+
+    /// Monetary precision used for input validation and output rounding [default: 4]
+    #[structopt(long)]
+    decimal_places: Option<u32>,
+
+    /// Keep trailing zeros of amounts in the report (`1.00` instead of `1`)
+    #[structopt(long)]
+    keep_scale: bool,
+
+    /// Reject amounts with more decimal places than allowed
+    #[structopt(long)]
+    strict: bool,
+
+    /// Amounts are integer counts of 1/10000 units
+    #[structopt(long)]
+    minor_units: bool,
+
+    /// Format of processing errors printed to stderr: plain or json [default: plain]
+    #[structopt(long)]
+    log_format: Option<LogFormat>,
+
+    /// Reject rows with a timestamp earlier than a previous row
+    #[structopt(long)]
+    check_order: bool,
+
+    /// Log and skip rows that can't be parsed instead of aborting
+    #[structopt(long)]
+    skip_bad_rows: bool,
+
+    /// Process only these clients (comma separated)
+    #[structopt(long, require_delimiter = true)]
+    only_clients: Option<Vec<ClientId>>,
+
+    /// Ignore these clients (comma separated)
+    #[structopt(long, require_delimiter = true)]
+    exclude_clients: Option<Vec<ClientId>>,
+
+    /// Warn when a deposit leaves more than this available on an account
+    #[structopt(long)]
+    balance_warning_threshold: Option<Decimal>,
+
+    /// Reject deposits that would take the total of an account above this
+    #[structopt(long)]
+    max_account_balance: Option<Decimal>,
+
+    /// Report errors and warnings as `tracing` events on stderr, up to this level
+    /// (error, warn, info, debug or trace). Replaces the plain/JSON error log.
+    #[structopt(long)]
+    log_level: Option<tracing::Level>,
+
+    /// Print the number of processed records to stderr periodically
+    #[structopt(long)]
+    progress: bool,
+
+    /// Format of the input file: csv or ndjson (one JSON transaction per line) [default: csv]
+    #[structopt(long)]
+    input_format: Option<InputFormat>,
+
+    /// Format of the report: csv or bincode (length prefixed binary records) [default: csv]
+    #[structopt(long)]
+    report_format: Option<ReportFormat>,
+
+    /// Start the report with a `# transponster vX generated at <time>` line, the output
+    /// is then not pure csv
+    #[structopt(long)]
+    with_metadata: bool,
+
+    /// Order report rows by this column descending: total or available [default: input order]
+    #[structopt(long)]
+    sort_by: Option<SortColumn>,
+
+    /// When to quote fields of a csv report: always, necessary or never [default: necessary]
+    #[structopt(long)]
+    quote_style: Option<QuoteStyle>,
+
+    /// What happens to the part of balances below the report precision: round, drop
+    /// (truncate) or sweep (truncate into a `dust` column) [default: round]
+    #[structopt(long)]
+    report_dust: Option<DustHandling>,
+
+    /// Fail if any account ends up with negative held funds, which indicates a bug in
+    /// the dispute accounting
+    #[structopt(long)]
+    verify_no_negative_held: bool,
+
+    /// Read the input twice to report disputes, resolves and chargebacks of transactions
+    /// that come later in the file as `DisputeBeforeTransaction`
+    #[structopt(long)]
+    prescan_ids: bool,
+
+    /// Detect the delimiter of the input file (comma, tab or semicolon) from its header
+    #[structopt(long)]
+    auto_delimiter: bool,
+
+    /// Accept amounts with a leading currency symbol and thousands separators, e.g. `$1,000.50`
+    #[structopt(long)]
+    lenient_amounts: bool,
+
+    /// Decimal separator of amounts: dot or comma (e.g. `"1.234,56"`) [default: dot]
+    #[structopt(long)]
+    decimal_locale: Option<DecimalLocale>,
+
+    /// Warn when a disputed withdrawal leaves the total above deposits minus withdrawals
+    #[structopt(long)]
+    warn_unbacked_disputes: bool,
+
+    /// After processing, warn about accounts whose total doesn't match their deposits,
+    /// withdrawals and disputes
+    #[structopt(long)]
+    reconcile: bool,
+
+    /// Hold only the available funds when a deposit is disputed, instead of making them negative
+    #[structopt(long)]
+    clamp_available_on_dispute: bool,
+
+    /// Ignore resolves of transactions that are not under dispute
+    #[structopt(long)]
+    lenient_resolves: bool,
+
+    /// Reject disputes of a transaction that was already disputed this many times
+    #[structopt(long)]
+    max_disputes_per_transaction: Option<u32>,
+
+    /// Allow a deposit and a withdrawal to use the same transaction id
+    #[structopt(long)]
+    dedupe_per_operation: bool,
+
+    /// Allow a deposit or withdrawal to reuse the id of a transaction whose disputes are
+    /// all resolved or charged back
+    #[structopt(long)]
+    reuse_settled_ids: bool,
+
+    /// Reject rows identical to an already applied one (operation, client, tx and amount)
+    #[structopt(long)]
+    dedupe_fingerprints: bool,
+
+    /// Reject dispute and resolve rows with an amount
+    #[structopt(long)]
+    reject_dispute_amounts: bool,
+
+    /// Reject deposits and withdrawals of zero
+    #[structopt(long)]
+    reject_zero_amount: bool,
+
+    /// Drop the transaction history of accounts locked for N records, keeping balances
+    #[structopt(long)]
+    evict_locked_after: Option<usize>,
+
+    /// Print at most N processing errors of each type, then only how many were left out
+    #[structopt(long)]
+    error_log_limit: Option<usize>,
+
+    /// Flush the report to stdout every N rows instead of only at the end
+    #[structopt(long)]
+    report_flush_interval: Option<usize>,
+
+    /// Add a `disputed` column with the number of open disputes of each account
+    #[structopt(long)]
+    detailed: bool,
+
+    /// Process only the first N records of the input
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// Rows that can't be parsed don't count towards `--limit`
+    #[structopt(long)]
+    limit_valid_only: bool,
+
+    /// Append a footer row with totals across all accounts (empty client column)
+    #[structopt(long)]
+    totals: bool,
+}
+
+impl Args {
+    // Flat flags replaced by subcommands, still accepted
+    fn deprecated_flags(&self) -> Vec<&'static str> {
+        [
+            ("verify", self.verify.is_some()),
+            ("diff", self.diff.is_some()),
+            ("explain", self.explain),
+            ("selftest", self.selftest),
+        ]
+        .into_iter()
+        .filter_map(|(flag, used)| used.then_some(flag))
+        .collect()
+    }
+
+    // Like other warnings, nothing is printed with `--quiet`
+    fn deprecation_warnings(&self) -> Vec<String> {
+        match self.quiet {
+            true => Vec::new(),
+            false => self
+                .deprecated_flags()
+                .into_iter()
+                .map(|flag| format!("`--{flag}` is deprecated, use `transponster {flag}` instead"))
+                .collect(),
+        }
+    }
+
+    // Flags can only switch settings on, so a file can't be overridden with `false`
+    fn engine_config(&self) -> Result<EngineConfig> {
+        let mut config = match &self.config {
+            Some(path) => EngineConfig::from_file(path)?,
+            None => EngineConfig::default(),
+        };
+
+        if let Some(decimal_places) = self.decimal_places {
+            config.decimal_places = decimal_places;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(input_format) = self.input_format {
+            config.input_format = input_format;
+        }
+        if let Some(report_format) = self.report_format {
+            config.report_format = report_format;
+        }
+        if let Some(quote_style) = self.quote_style {
+            config.report_quote_style = quote_style;
+        }
+        if let Some(dust) = self.report_dust {
+            config.report_dust = dust;
+        }
+        if let Some(column) = self.sort_by {
+            config.report_sort = Some(column);
+        }
+        if let Some(decimal_locale) = self.decimal_locale {
+            config.decimal_locale = decimal_locale;
+        }
+        if let Some(ids) = &self.only_clients {
+            config.only_clients = Some(ids.iter().copied().collect());
+        }
+        if let Some(ids) = &self.exclude_clients {
+            config.exclude_clients = Some(ids.iter().copied().collect());
+        }
+        if let Some(threshold) = self.balance_warning_threshold {
+            config.balance_warning_threshold = Some(threshold);
+        }
+        if let Some(cap) = self.max_account_balance {
+            config.max_account_balance = Some(cap);
+        }
+        if let Some(interval) = self.report_flush_interval {
+            config.report_flush_interval = Some(interval);
+        }
+        if let Some(limit) = self.error_log_limit {
+            config.error_log_limit = Some(limit);
+        }
+        if let Some(records) = self.evict_locked_after {
+            config.evict_locked_after = Some(records);
+        }
+        if let Some(limit) = self.max_disputes_per_transaction {
+            config.max_disputes_per_transaction = Some(limit);
+        }
+        if let Some(limit) = self.limit {
+            config.record_limit = Some(limit);
+        }
+        if self.limit_valid_only {
+            config.limit_counts_malformed = false;
+        }
+        if self.keep_scale {
+            config.normalize_amounts = false;
+        }
+        if self.progress {
+            config.progress_interval = Some(PROGRESS_INTERVAL);
+        }
+
+        config.strict |= self.strict;
+        config.amounts_in_minor_units |= self.minor_units;
+        config.check_chronology |= self.check_order;
+        config.skip_bad_rows |= self.skip_bad_rows;
+        config.verbose |= self.verbose;
+        config.quiet |= self.quiet;
+        config.detailed_report |= self.detailed;
+        config.dedupe_per_operation |= self.dedupe_per_operation;
+        config.reuse_settled_ids |= self.reuse_settled_ids;
+        config.dedupe_fingerprints |= self.dedupe_fingerprints;
+        config.report_totals |= self.totals;
+        config.reject_zero_amount |= self.reject_zero_amount;
+        config.reject_dispute_amounts |= self.reject_dispute_amounts;
+        config.auto_delimiter |= self.auto_delimiter;
+        config.verify_no_negative_held |= self.verify_no_negative_held;
+        config.prescan_transaction_ids |= self.prescan_ids;
+        config.report_metadata |= self.with_metadata;
+        config.lenient_amounts |= self.lenient_amounts;
+        config.warn_unbacked_disputes |= self.warn_unbacked_disputes;
+        config.lenient_resolves |= self.lenient_resolves;
+        config.clamp_available_on_dispute |= self.clamp_available_on_dispute;
+
+        Ok(config)
+    }
+}
+
+// Generated from the enum so new operations show up automatically
+fn explain() -> String {
+    OperationType::iter()
+        .map(|operation| {
+            format!(
+                "{:<12}{}\n",
+                operation.to_string().to_lowercase(),
+                operation.description()
+            )
+        })
+        .collect()
 }
 
 fn main() -> Result<()> {
     // It's probably too much but it provides nice guides
-    let args = Args::from_args_safe()?;
+    match Cli::from_args_safe()?.into_command() {
+        Command::Process(args) => {
+            for warning in args.deprecation_warnings() {
+                eprintln!("Warning: {warning}");
+            }
+            process(args)
+        }
+        Command::Verify { report, mut args } => {
+            args.verify = Some(report);
+            process(args)
+        }
+        Command::Diff { old, new } => print_diff(&old, &new),
+        Command::Explain => {
+            print!("{}", explain());
+            Ok(())
+        }
+        Command::Selftest => selftest(),
+    }
+}
 
+// The report is compared as text, so differences in decimal formatting show up too
+fn selftest() -> Result<()> {
     let mut engine = Engine::new();
+    engine.set_error_sink(Box::new(std::io::sink()));
+    engine.process_from_reader(reader_builder().from_reader(SELFTEST_INPUT.as_bytes()))?;
 
-    engine.process_input(&args.input)?;
+    let mut report = Vec::new();
+    engine.serialize_report_to_writer(&mut report)?;
+    let report = String::from_utf8(report)?;
 
-    engine.serialize_report_stdout()?;
+    if report != SELFTEST_REPORT {
+        eprintln!("Expected report:\n{SELFTEST_REPORT}");
+        eprintln!("Actual report:\n{report}");
+        bail!("Self-test failed");
+    }
+    eprintln!("Self-test passed");
+    Ok(())
+}
+
+fn print_diff(old: &Path, new: &Path) -> Result<()> {
+    for line in diff_report_files(old, new)? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+// `--diff`, `--explain` and `--selftest` flags are kept for compatibility with the flat
+// interface
+fn process(args: Args) -> Result<()> {
+    if let Some([old, new]) = args.diff.as_deref() {
+        return print_diff(old, new);
+    }
+    if args.selftest {
+        return selftest();
+    }
+
+    let input = match &args.input {
+        Some(input) if !args.explain => input,
+        _ => {
+            print!("{}", explain());
+            return Ok(());
+        }
+    };
+
+    let config = args.engine_config()?;
+    let quiet = config.quiet;
+    let is_zip = input
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+    if args.watch && (config.input_format != InputFormat::Csv || is_zip) {
+        bail!("--watch only supports csv input files");
+    }
+    let mut engine = Engine::with_config(config);
+
+    if let Some(level) = args.log_level {
+        tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_writer(std::io::stderr)
+            .init();
+        engine.set_error_sink(Box::new(std::io::sink()));
+    }
+
+    if let Some(path) = &args.replay_log {
+        engine.set_replay_log(Box::new(BufWriter::new(File::create(path)?)));
+    }
+
+    if let Some(path) = &args.trace {
+        engine.set_trace(Box::new(BufWriter::new(File::create(path)?)));
+    }
+
+    if let Some(path) = &args.error_log {
+        let is_json = path.extension().is_some_and(|extension| {
+            ["ndjson", "jsonl", "json"]
+                .iter()
+                .any(|json| extension.eq_ignore_ascii_case(json))
+        });
+        let format = match is_json {
+            true => ErrorLogFormat::Ndjson,
+            false => ErrorLogFormat::Csv,
+        };
+        engine.set_error_log(Box::new(BufWriter::new(File::create(path)?)), format)?;
+    }
+
+    if let Some(report) = &args.merge_report {
+        engine.load_report(report)?;
+    }
+
+    // Ctrl-C stops reading, the report of what was processed so far is still written
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::Relaxed))?;
+    engine.set_stop_flag(interrupted);
+
+    if args.watch {
+        engine.watch_input(input, WATCH_POLL_INTERVAL)?;
+    } else {
+        engine.process_input(input)?;
+    }
+    if engine.is_stopped() && !args.watch && !quiet {
+        eprintln!(
+            "Interrupted, the report only covers the first {} records",
+            engine.stats().read
+        );
+    }
+
+    if args.reconcile {
+        engine.reconcile_accounts();
+    }
+
+    let verified = match &args.verify {
+        Some(expected) => verify_report(&engine, expected),
+        None => engine.serialize_report_stdout().map_err(Into::into),
+    };
+
+    for warning in engine.warnings().iter().filter(|_| !quiet) {
+        if args.log_level.is_some() {
+            tracing::warn!("{warning}");
+        } else {
+            eprintln!("Warning: {warning}");
+        }
+    }
+
+    if args.verbose && !quiet {
+        if engine.stats().read == 0 {
+            eprintln!("Input contains no records");
+        }
+        eprintln!("{}", engine.stats());
+
+        for missing in engine.missing_transaction_ids() {
+            eprintln!(
+                "Missing transaction ids: {}-{}",
+                missing.start(),
+                missing.end()
+            );
+        }
+    }
+
+    verified
+}
+
+fn diff_report_files(old: &Path, new: &Path) -> Result<Vec<String>> {
+    let old = read_report(reader_builder().from_path(old)?)?;
+    let new = read_report(reader_builder().from_path(new)?)?;
+    Ok(report_deltas(&old, &new))
+}
+
+fn verify_report(engine: &Engine, expected: &Path) -> Result<()> {
+    let expected_rows = read_report(reader_builder().from_path(expected)?)?;
+    let differences = diff_reports(&expected_rows, &engine.report_rows()?);
+
+    for difference in &differences {
+        eprintln!("{difference}");
+    }
+    if !differences.is_empty() {
+        bail!("Report doesn't match {}", expected.display());
+    }
 
     Ok(())
 }
@@ -31,9 +660,16 @@ fn main() -> Result<()> {
 mod tests {
     use std::io::BufWriter;
 
-    use csv::{ReaderBuilder, Trim, Writer};
+    use csv::{ReaderBuilder, Trim};
+    use rust_decimal_macros::dec;
+    use structopt::StructOpt;
+    use strum::IntoEnumIterator;
+
+    use transponster::engine::config::EngineConfig;
+    use transponster::engine::models::{OperationType, ProcessingStats};
+    use transponster::engine::Engine;
 
-    use crate::engine::Engine;
+    use crate::{diff_report_files, explain, selftest, verify_report, Args, Cli, Command};
 
     #[test]
     fn simple_input() {
@@ -69,21 +705,574 @@ mod tests {
         assert_eq!(result, "client,available,held,total,locked\n1,2,0,2,true\n");
     }
 
-    fn run_test(input: &str) -> String {
+    #[test]
+    fn uppercase_headers() {
+        let input = "\
+        TYPE,CLIENT,TX,AMOUNT
+        deposit, 1, 1, 1.0
+        withdrawal, 1, 2, 0.5";
+
+        let result = run_test(input);
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,0.5,0,0.5,false\n"
+        );
+    }
+
+    #[test]
+    fn mixed_case_reordered_headers() {
+        let input = "\
+        TYPE, AMOUNT, Client, Tx
+        deposit, 1.0, 1, 1
+        withdrawal, 0.5, 1, 2";
+
+        let result = run_test(input);
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,0.5,0,0.5,false\n"
+        );
+    }
+
+    #[test]
+    fn amounts_parsed_from_text() {
+        let input = "\
+        type, client, tx, amount
+        deposit, 1, 1, 1.50
+        deposit, 1, 2, 1e2
+        deposit, 2, 3, 99999999999999999999999999999
+        deposit, 2, 4, 2.000";
+
+        let args = Args::from_iter(["transponster", "--skip-bad-rows", "input.csv"]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,101.5,0,101.5,false\n2,2,0,2,false\n"
+        );
+    }
+
+    #[test]
+    fn client_filters_from_args() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, 2.0
+            deposit, 3, 3, 3.0";
+
+        let args = Args::from_iter([
+            "transponster",
+            "--only-clients",
+            "1,3",
+            "--exclude-clients",
+            "3",
+            "input.csv",
+        ]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,1,0,1,false\n"
+        );
+    }
+
+    #[test]
+    fn timestamp_column() {
+        let input = "\
+            type, client, tx, amount, timestamp
+            deposit, 1, 1, 2.0, 2024-05-01T10:00:00Z
+            withdrawal, 1, 2, 1.0, 2024-04-30T10:00:00Z
+            withdrawal, 1, 3, 0.5,";
+
+        let args = Args::from_iter(["transponster", "--check-order", "input.csv"]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, 2.0
+            withdrawal, 1, 3, 0.5
+            deposit, 3, 4, 3.0
+            deposit, 1, 5, 4.0";
+
+        let args = Args::from_iter(["transponster", "--limit", "3", "input.csv"]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(engine.stats().read, 3);
+        assert_eq!(
+            report(&engine),
+            "client,available,held,total,locked\n1,0.5,0,0.5,false\n2,2,0,2,false\n"
+        );
+    }
+
+    #[test]
+    fn limit_valid_only() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 2, 2, abc
+            deposit, 2, 3, 2.0
+            deposit, 3, 4, 3.0";
+
+        let args = Args::from_iter([
+            "transponster",
+            "--limit",
+            "2",
+            "--skip-bad-rows",
+            "input.csv",
+        ]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+        assert_eq!(engine.stats().read, 2);
+        assert_eq!(engine.stats().applied, 1);
+
+        let args = Args::from_iter([
+            "transponster",
+            "--limit",
+            "2",
+            "--limit-valid-only",
+            "--skip-bad-rows",
+            "input.csv",
+        ]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+        assert_eq!(engine.stats().read, 3);
+        assert_eq!(engine.stats().applied, 2);
+    }
+
+    #[test]
+    fn detailed_report() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 1, 2, 2.0
+            dispute, 1, 1
+            deposit, 2, 3, 1.5";
+
+        let args = Args::from_iter(["transponster", "--detailed", "--totals", "input.csv"]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked,disputed\n1,2,1,3,false,1\n2,1.5,0,1.5,false,0\n,3.5,1,4.5,,\n"
+        );
+
+        // Default output is unchanged
+        assert_eq!(
+            run_test(input),
+            "client,available,held,total,locked\n1,2,1,3,false\n2,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn more_complex_input_totals() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 1, 2, 2.0
+            dispute, 1, 1
+            chargeback, 1, 1
+            withdrawal, 1, 3, 1
+            deposit, 1, 3, 2.0
+            deposit, 2, 4, 1.5
+            deposit, 3, 5, 0.25";
+
+        let args = Args::from_iter(["transponster", "--totals", "input.csv"]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+
+        let totals = engine.totals().unwrap();
+        assert_eq!(totals.available, dec!(3.75));
+        assert_eq!(totals.held, dec!(0));
+        assert_eq!(totals.total, dec!(3.75));
+        assert_eq!(
+            report(&engine),
+            "client,available,held,total,locked\n1,2,0,2,true\n2,1.5,0,1.5,false\n3,0.25,0,0.25,false\n,3.75,0,3.75,\n"
+        );
+    }
+
+    #[test]
+    fn more_complex_input_stats() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 1, 2, 2.0
+            dispute, 1, 1
+            chargeback, 1, 1
+            withdrawal, 1, 3, 1
+            deposit, 1, 3, 2.0";
+
+        let engine = run_engine(input);
+
+        assert_eq!(
+            engine.stats(),
+            ProcessingStats {
+                read: 6,
+                applied: 4,
+                skipped: 2,
+                filtered: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn client_with_only_failed_operations() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            dispute, 2, 7";
+
+        let result = run_test(input);
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,1,0,1,false\n2,0,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn header_only_input() {
+        let input = "type, client, tx, amount";
+
+        let engine = run_engine(input);
+        assert_eq!(engine.stats().read, 0);
+
+        let result = run_test(input);
+        assert_eq!(result, "client,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn empty_input_file() {
+        let path = std::env::temp_dir().join("transponster_empty_input.csv");
+        std::fs::write(&path, "").unwrap();
+
+        let mut engine = Engine::new();
+        engine.process_input(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(engine.stats().read, 0);
+        assert_eq!(report(&engine), "client,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn auto_delimiter() {
+        let args = Args::from_iter(["transponster", "--auto-delimiter", "input.csv"]);
+        let fixtures = [
+            ("comma", "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,2,1\nwithdrawal,1,3,1\n"),
+            ("tab", "type\tclient\ttx\tamount\ndeposit\t1\t1\t2.5\ndeposit\t2\t2\t1\nwithdrawal\t1\t3\t1\n"),
+            ("semicolon", "type;client;tx;amount\ndeposit;1;1;2.5\ndeposit;2;2;1\nwithdrawal;1;3;1\n"),
+        ];
+
+        for (name, input) in fixtures {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), input).unwrap();
+
+            let mut engine = Engine::with_config(args.engine_config().unwrap());
+            engine.process_input(&file.path().to_path_buf()).unwrap();
+
+            assert_eq!(
+                report(&engine),
+                "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,1,0,1,false\n",
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_places_rounds_output() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.005
+            deposit, 2, 2, 2.125";
+
+        let args = Args::from_iter(["transponster", "--decimal-places", "2", "input.csv"]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,1,0,1,false\n2,2.12,0,2.12,false\n"
+        );
+    }
+
+    #[test]
+    fn decimal_places_strict_rejects_precise_amounts() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 1.005
+            deposit, 1, 2, 2.120";
+
+        let args = Args::from_iter([
+            "transponster",
+            "--decimal-places",
+            "2",
+            "--strict",
+            "input.csv",
+        ]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,2.12,0,2.12,false\n"
+        );
+    }
+
+    #[test]
+    fn minor_units_input() {
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 15000
+            deposit, 2, 2, 1.5";
+
+        let args = Args::from_iter(["transponster", "--minor-units", "input.csv"]);
+        let result = run_test_with_config(input, args.engine_config().unwrap());
+
+        assert_eq!(
+            result,
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,0,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn config_file_with_cli_override() {
+        let path = std::env::temp_dir().join("transponster_config_test.toml");
+        std::fs::write(&path, "strict = true\ndecimal_places = 2\n").unwrap();
+
+        // Only strict mode rejects the too precise withdrawal, the balance covers it
+        let input = "\
+            type, client, tx, amount
+            deposit, 1, 1, 5.0
+            withdrawal, 1, 2, 1.005
+            withdrawal, 1, 3, 1.5";
+
+        let args = Args::from_iter([
+            "transponster",
+            "--config",
+            path.to_str().unwrap(),
+            "input.csv",
+        ]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+        assert_eq!(engine.stats().skipped, 1);
+        assert_eq!(
+            report(&engine),
+            "client,available,held,total,locked\n1,3.5,0,3.5,false\n"
+        );
+
+        std::fs::write(&path, "decimal_places = 2\n").unwrap();
+        let args = Args::from_iter([
+            "transponster",
+            "--config",
+            path.to_str().unwrap(),
+            "input.csv",
+        ]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+        assert_eq!(engine.stats().skipped, 0);
+        assert_eq!(
+            report(&engine),
+            "client,available,held,total,locked\n1,2.5,0,2.5,false\n"
+        );
+
+        std::fs::write(&path, "strict = true\ndecimal_places = 2\n").unwrap();
+
+        let args = Args::from_iter([
+            "transponster",
+            "--config",
+            path.to_str().unwrap(),
+            "--decimal-places",
+            "3",
+            "input.csv",
+        ]);
+        let engine = run_engine_with_config(input, args.engine_config().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            report(&engine),
+            "client,available,held,total,locked\n1,2.495,0,2.495,false\n"
+        );
+    }
+
+    #[test]
+    fn explain_lists_all_operations() {
+        let explanation = explain();
+
+        for operation in OperationType::iter() {
+            let line = format!(
+                "{:<12}{}",
+                operation.to_string().to_lowercase(),
+                operation.description()
+            );
+            assert!(explanation.contains(&line), "missing {operation}");
+        }
+        assert_eq!(explanation.lines().count(), OperationType::iter().count());
+        assert!(Args::from_iter_safe(["transponster", "--explain"]).is_ok());
+    }
+
+    #[test]
+    fn verify_against_report_file() {
+        let input = "\
+        type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0";
+        let engine = run_engine(input);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "client,available,held,total,locked\n2,2.0,0,2,false\n1,1,0,1,false\n",
+        )
+        .unwrap();
+        assert!(verify_report(&engine, file.path()).is_ok());
+
+        std::fs::write(
+            file.path(),
+            "client,available,held,total,locked\n1,1,0,1,true\n2,2,0,2,false\n",
+        )
+        .unwrap();
+        assert!(verify_report(&engine, file.path()).is_err());
+    }
+
+    #[test]
+    fn process_subcommand_matches_bare_invocation() {
+        let input = "\
+        type, client, tx, amount
+        deposit, 1, 1, 2.0
+        withdrawal, 1, 2, 0.5
+        deposit, 2, 3, 1";
+
+        let parse = |argv: &[&str]| match Cli::from_iter_safe(argv).unwrap().into_command() {
+            Command::Process(args) => args,
+            command => panic!("unexpected command {command:?}"),
+        };
+        let bare = parse(&[
+            "transponster",
+            "--totals",
+            "--decimal-places",
+            "2",
+            "input.csv",
+        ]);
+        let explicit = parse(&[
+            "transponster",
+            "process",
+            "--totals",
+            "--decimal-places",
+            "2",
+            "input.csv",
+        ]);
+
+        assert_eq!(bare.input, explicit.input);
+        assert_eq!(
+            report(&run_engine_with_config(
+                input,
+                bare.engine_config().unwrap()
+            )),
+            report(&run_engine_with_config(
+                input,
+                explicit.engine_config().unwrap()
+            ))
+        );
+
+        let verify = Cli::from_iter_safe(["transponster", "verify", "report.csv", "input.csv"])
+            .unwrap()
+            .into_command();
+        assert!(matches!(verify, Command::Verify { args, .. } if args.input.is_some()));
+    }
+
+    #[test]
+    fn diff_two_report_files() {
+        let old = tempfile::NamedTempFile::new().unwrap();
+        let new = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            old.path(),
+            "client,available,held,total,locked\n1,1.0,0,1,false\n2,5,0,5,false\n3,1,0,1,false\n",
+        )
+        .unwrap();
+        std::fs::write(
+            new.path(),
+            "client,available,held,total,locked\n2,2.5,1,3.5,true\n1,1,0,1,false\n4,1,0,1,false\n",
+        )
+        .unwrap();
+
+        let args = Args::from_iter_safe(["transponster", "--diff", "old.csv", "new.csv"]).unwrap();
+        assert!(args.input.is_none());
+
+        assert_eq!(
+            diff_report_files(old.path(), new.path()).unwrap(),
+            [
+                "client 2: available -2.5, held +1, total -1.5, locked false -> true",
+                "added clients: 4",
+                "removed clients: 3",
+            ]
+        );
+    }
+
+    fn run_engine(input: &str) -> Engine {
+        run_engine_with_config(input, EngineConfig::default())
+    }
+
+    fn run_engine_with_config(input: &str, config: EngineConfig) -> Engine {
         let reader = ReaderBuilder::new()
             .flexible(true)
             .trim(Trim::All)
             .from_reader(input.as_bytes());
 
-        let mut engine = Engine::new();
+        let mut engine = Engine::with_config(config);
         engine.process_from_reader(reader).unwrap();
 
+        engine
+    }
+
+    fn run_test(input: &str) -> String {
+        run_test_with_config(input, EngineConfig::default())
+    }
+
+    fn run_test_with_config(input: &str, config: EngineConfig) -> String {
+        report(&run_engine_with_config(input, config))
+    }
+
+    fn report(engine: &Engine) -> String {
         let buffer = Vec::new();
         let mut buf_writer = BufWriter::new(buffer);
-        let writer = Writer::from_writer(&mut buf_writer);
 
-        engine.serialize_report_to_writer(writer).unwrap();
+        engine.serialize_report_to_writer(&mut buf_writer).unwrap();
 
         String::from_utf8_lossy(&buf_writer.into_inner().unwrap()).into_owned()
     }
+
+    #[test]
+    fn selftest_passes() {
+        selftest().unwrap();
+        let parse = |argv: &[&str]| Cli::from_iter_safe(argv).unwrap().into_command();
+        assert!(matches!(
+            parse(&["transponster", "selftest"]),
+            Command::Selftest
+        ));
+        assert!(matches!(
+            parse(&["transponster", "--selftest"]),
+            Command::Process(Args { selftest: true, .. })
+        ));
+    }
+
+    #[test]
+    fn flat_command_flags_are_deprecated() {
+        let mut help = Vec::new();
+        Cli::clap().write_long_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        for flag in ["--verify ", "--diff", "--explain", "--selftest"] {
+            assert!(!help.contains(flag), "{flag} in help");
+        }
+
+        let args = Args::from_iter(["transponster", "--explain", "--verify", "r.csv"]);
+        assert_eq!(args.deprecated_flags(), ["verify", "explain"]);
+        assert_eq!(args.deprecation_warnings().len(), 2);
+        let args = Args::from_iter(["transponster", "input.csv"]);
+        assert!(args.deprecated_flags().is_empty());
+
+        let args = Args::from_iter(["transponster", "--quiet", "--explain"]);
+        assert!(args.deprecation_warnings().is_empty());
+    }
 }